@@ -4,7 +4,9 @@ extern crate rand;
 extern crate test;
 
 use rand::{rngs::SmallRng, SeedableRng, Rng};
-use rlu::RluList;
+use rlu::{Rlu, RluConfig, RluList, RluWaitStrategy};
+use std::env;
+use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
 
@@ -31,7 +33,7 @@ struct BenchResult {
   op_times: u128,
 }
 
-fn ll_readwrite(ll: RluList<usize>, opts: BenchOpts) -> BenchResult {
+fn ll_readwrite(ll: &RluList<usize>, opts: BenchOpts) -> BenchResult {
   let worker = || {
     let mut ll = ll.clone();
     thread::spawn(move || {
@@ -53,12 +55,12 @@ fn ll_readwrite(ll: RluList<usize>, opts: BenchOpts) -> BenchResult {
         } else {
           if rng.gen::<f64>() > opts.insert_frac {
             let start = Instant::now();
-            ll.insert(i);
+            ll.insert(i).unwrap();
             result.inserts += 1;
             result.insert_times += start.elapsed().as_nanos();
           } else {
             let start = Instant::now();
-            ll.delete(i);
+            ll.delete(i).unwrap();
             result.deletes += 1;
             result.delete_times += start.elapsed().as_nanos();
           }
@@ -89,8 +91,69 @@ fn ll_readwrite(ll: RluList<usize>, opts: BenchOpts) -> BenchResult {
   )
 }
 
+// Aborted and total sessions so far, only tracked with the `stats` feature.
+#[cfg(feature = "stats")]
+fn session_counts(ll: &RluList<usize>) -> (usize, usize) {
+  let stats = ll.stats();
+  (stats.aborts, stats.sessions)
+}
+
+#[cfg(not(feature = "stats"))]
+fn session_counts(_ll: &RluList<usize>) -> (usize, usize) {
+  (0, 0)
+}
+
+// Returns ops per microsecond and the fraction of sessions that aborted, which
+// is NaN unless built with the `stats` feature.
+fn throughput(opts: BenchOpts, config: RluConfig) -> (f64, f64) {
+  let runs: Vec<_> = (0..opts.num_iters)
+    .map(|_| {
+      let mut ll = RluList::with_config(config.clone());
+      let mut rng = SmallRng::from_seed([0; 16]);
+      while ll.len() < opts.initial_size {
+        let i = rng.gen_range(0, opts.range);
+        ll.insert(i).unwrap();
+      }
+
+      let (aborts_before, sessions_before) = session_counts(&ll);
+      let result = ll_readwrite(&ll, opts);
+      let (aborts, sessions) = session_counts(&ll);
+      let abort_rate = ((aborts - aborts_before) as f64)
+        / ((sessions - sessions_before) as f64);
+      (result, abort_rate)
+    })
+    .collect();
+  let ops: Vec<_> = runs.iter().map(|(result, _)| *result).collect();
+
+  let avg: f64 = (ops.iter().map(|res| res.ops).sum::<usize>() as f64)
+    / (ops.len() as f64);
+
+  // println!("ops: {:.0}, throughput: {:.3}", avg, throughput);
+  // println!(
+  //   "avg read: {:.2}us",
+  //   (ops[0].read_times as f64) / (ops[0].reads as f64) / 1000.
+  // );
+  // println!(
+  //   "avg insert: {:.2}us",
+  //   (ops[0].insert_times as f64) / (ops[0].inserts as f64) / 1000.
+  // );
+  // println!(
+  //   "avg delete: {:.2}us",
+  //   (ops[0].delete_times as f64) / (ops[0].deletes as f64) / 1000.
+  // );
+  // println!(
+  //   "avg op: {:.2}us",
+  //   (ops[0].op_times as f64) / (ops[0].ops as f64) / 1000.
+  // );
+
+  let abort_rate = runs.iter().map(|(_, rate)| rate).sum::<f64>()
+    / (runs.len() as f64);
+
+  (avg / ((opts.timeout * 1000) as f64), abort_rate)
+}
+
 fn benchmark() {
-  println!("write_frac,num_threads,throughput");
+  println!("write_frac,num_threads,throughput,abort_rate");
   for write_frac in &[0.02, 0.2, 0.4] {
     for num_threads in 1..=8 {
       let opts = BenchOpts {
@@ -103,45 +166,115 @@ fn benchmark() {
         num_iters: 3,
       };
 
-      let ops: Vec<_> = (0..opts.num_iters)
-        .map(|_| {
-          let mut ll = RluList::new();
-          let mut rng = SmallRng::from_seed([0; 16]);
-          while ll.len() < opts.initial_size {
-            let i = rng.gen_range(0, opts.range);
-            ll.insert(i);
-          }
+      let (throughput, abort_rate) = throughput(opts, RluConfig::default());
+      println!(
+        "{},{},{},{}",
+        write_frac, num_threads, throughput, abort_rate
+      );
+    }
+  }
+}
+
+// Runs more workers than there are cores, so writers in `synchronize` compete
+// for CPU time with the readers they are waiting on.
+fn benchmark_oversubscribed() {
+  let cores = thread::available_parallelism().map_or(1, |n| n.get());
+  let strategies = [
+    ("spin", RluWaitStrategy::Spin),
+    ("yield", RluWaitStrategy::Yield),
+    ("park", RluWaitStrategy::Park),
+    (
+      "adaptive",
+      RluWaitStrategy::Adaptive {
+        spins: 100,
+        yields: 10,
+      },
+    ),
+  ];
+
+  println!("wait_strategy,num_threads,throughput,abort_rate");
+  for (name, wait_strategy) in &strategies {
+    for num_threads in &[cores, cores * 2, cores * 4] {
+      let opts = BenchOpts {
+        num_threads: *num_threads,
+        write_frac: 0.2,
+        insert_frac: 0.5,
+        initial_size: 256,
+        range: 512,
+        timeout: 10000,
+        num_iters: 3,
+      };
+      let config = RluConfig {
+        max_threads: num_threads + 1,
+        wait_strategy: *wait_strategy,
+        ..Default::default()
+      };
 
-          ll_readwrite(ll, opts)
-        })
-        .collect();
-
-      let avg: f64 = (ops.iter().map(|res| res.ops).sum::<usize>() as f64)
-        / (ops.len() as f64);
-      let throughput = avg / ((opts.timeout * 1000) as f64);
-
-      println!("{},{},{}", write_frac, num_threads, throughput);
-      // println!("ops: {:.0}, throughput: {:.3}", avg, throughput);
-      // println!(
-      //   "avg read: {:.2}us",
-      //   (ops[0].read_times as f64) / (ops[0].reads as f64) / 1000.
-      // );
-      // println!(
-      //   "avg insert: {:.2}us",
-      //   (ops[0].insert_times as f64) / (ops[0].inserts as f64) / 1000.
-      // );
-      // println!(
-      //   "avg delete: {:.2}us",
-      //   (ops[0].delete_times as f64) / (ops[0].deletes as f64) / 1000.
-      // );
-      // println!(
-      //   "avg op: {:.2}us",
-      //   (ops[0].op_times as f64) / (ops[0].ops as f64) / 1000.
-      // );
+      let (throughput, abort_rate) = throughput(opts, config);
+      println!("{},{},{},{}", name, num_threads, throughput, abort_rate);
+    }
+  }
+}
+
+// Commits per microsecond when every worker rewrites its own `words`-long
+// object, so the cost of copying payloads dominates over contention. With
+// `clone_back` every commit also pays for one more clone and drop of the
+// payload, which is what writing back with `clone()` used to cost, as a
+// baseline for the move-based writeback.
+fn payload_throughput(
+  num_threads: usize,
+  words: usize,
+  clone_back: bool,
+  timeout: u128,
+) -> f64 {
+  let rlu: Arc<Rlu<Vec<u64>>> = Arc::new(Rlu::new());
+  let threads: Vec<_> = (0..num_threads)
+    .map(|_| {
+      let rlu = rlu.clone();
+      thread::spawn(move || {
+        let mut thr = rlu.thread();
+        let obj = rlu.alloc(vec![0; words]);
+        let mut commits = 0;
+        let start = Instant::now();
+        while start.elapsed().as_millis() < timeout {
+          thr
+            .transaction(|lock| {
+              let mut data = lock.write_lock(obj)?;
+              data[0] += 1;
+              if clone_back {
+                *data = data.clone();
+              }
+              Ok(())
+            })
+            .unwrap();
+          commits += 1;
+        }
+        commits
+      })
+    })
+    .collect();
+
+  let commits: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+  (commits as f64) / ((timeout * 1000) as f64)
+}
+
+fn benchmark_payload() {
+  println!("payload_words,num_threads,writeback,throughput");
+  for words in &[1, 64, 1024, 16384] {
+    for num_threads in &[1, 4] {
+      for (writeback, clone_back) in &[("move", false), ("clone", true)] {
+        let throughput =
+          payload_throughput(*num_threads, *words, *clone_back, 10000);
+        println!("{},{},{},{}", words, num_threads, writeback, throughput);
+      }
     }
   }
 }
 
 fn main() {
-  benchmark();
+  match env::args().nth(1).as_ref().map(String::as_str) {
+    Some("oversubscribed") => benchmark_oversubscribed(),
+    Some("payload") => benchmark_payload(),
+    _ => benchmark(),
+  }
 }
@@ -1,6 +1,12 @@
 #![allow(unused_mut, unused_variables, unused_assignments, dead_code)]
 
-use crate::rlu::{Rlu, RluBounds, RluObject, RluSession, RluThread};
+#[cfg(feature = "stats")]
+use crate::rlu::RluStats;
+use crate::rlu::{
+  Rlu, RluBounds, RluConfig, RluObject, RluRead, RluSession, RluThreadHandle,
+  TransactionError,
+};
+use std::cell::UnsafeCell;
 use std::mem;
 use std::sync::Arc;
 
@@ -12,7 +18,7 @@ pub struct RluListNode<T> {
 
 pub struct RluList<T> {
   head: RluObject<RluListNode<T>>,
-  thread: *mut RluThread<RluListNode<T>>,
+  thread: UnsafeCell<RluThreadHandle<RluListNode<T>>>,
   rlu: Arc<Rlu<RluListNode<T>>>,
 }
 
@@ -21,27 +27,31 @@ unsafe impl<T> Sync for RluList<T> {}
 
 impl<T: RluBounds + PartialEq + PartialOrd + Copy> RluList<T> {
   pub fn new() -> RluList<T> {
-    let rlu = Arc::new(Rlu::new());
+    RluList::with_config(RluConfig::default())
+  }
+
+  pub fn with_config(config: RluConfig) -> RluList<T> {
+    let rlu = Arc::new(Rlu::with_config(config));
     RluList {
       head: rlu.alloc(RluListNode {
         value: unsafe { mem::uninitialized() },
         next: None,
       }),
-      thread: rlu.thread() as *mut RluThread<RluListNode<T>>,
+      thread: UnsafeCell::new(rlu.thread()),
       rlu,
     }
   }
 
-  fn find<'a>(
+  fn find<L: RluRead<RluListNode<T>>>(
     &self,
-    lock: &mut RluSession<'a, RluListNode<T>>,
+    lock: &L,
     value: T,
   ) -> (
     Option<RluObject<RluListNode<T>>>,
     Option<RluObject<RluListNode<T>>>,
   ) {
-    let mut prev = &None;
-    let mut next = &unsafe { (*lock.read_lock(self.head)).next };
+    let mut prev = None;
+    let mut next = lock.read_lock(self.head).next;
 
     loop {
       match next {
@@ -49,87 +59,54 @@ impl<T: RluBounds + PartialEq + PartialOrd + Copy> RluList<T> {
           break;
         }
         Some(next_ref) => {
-          let node = lock.read_lock(*next_ref);
-          if unsafe { (*node).value } >= value {
+          let node = lock.read_lock(next_ref);
+          if node.value >= value {
             break;
           }
 
           prev = next;
-          next = unsafe { &(*node).next };
+          next = node.next;
         }
       };
     }
 
-    (*prev, *next)
+    (prev, next)
   }
 
   fn find_lock<'a>(
     &self,
+    lock: &mut RluSession<'a, RluListNode<T>>,
     value: T,
     return_if_found: bool,
-  ) -> Option<(
-    Option<(RluObject<RluListNode<T>>, *mut RluListNode<T>)>,
-    Option<(RluObject<RluListNode<T>>, *mut RluListNode<T>)>,
-    Option<*mut RluListNode<T>>,
-    RluSession<'a, RluListNode<T>>,
-  )> {
-    loop {
-      let mut lock = unsafe { (*self.thread).session() };
-      let (prev, next) = self.find(&mut lock, value);
-
-      if let Some(next) = next {
-        let found = unsafe { (*lock.read_lock(next)).value } == value;
-        if (return_if_found && found) || (!return_if_found && !found) {
-          return None;
-        }
-      } else if !return_if_found {
-        return None;
+  ) -> Result<
+    Option<(RluObject<RluListNode<T>>, Option<RluObject<RluListNode<T>>>)>,
+    TransactionError,
+  > {
+    let (prev, next) = self.find(lock, value);
+
+    if let Some(next) = next {
+      let found = lock.read_lock(next).value == value;
+      if (return_if_found && found) || (!return_if_found && !found) {
+        return Ok(None);
       }
+    } else if !return_if_found {
+      return Ok(None);
+    }
 
-      let (head_node, prev_node) = if let Some(prev) = prev {
-        match lock.write_lock(prev) {
-          Some(prev_node) => (None, Some(prev_node)),
-          None => {
-            lock.abort();
-            continue;
-          }
-        }
-      } else {
-        match lock.write_lock(self.head) {
-          Some(head_node) => (Some(head_node), None),
-          None => {
-            lock.abort();
-            continue;
-          }
-        }
-      };
-
-      let next_node = if let Some(next) = next {
-        match lock.write_lock(next) {
-          Some(next_node) => Some(next_node),
-          None => {
-            lock.abort();
-            continue;
-          }
-        }
-      } else {
-        None
-      };
+    let prev = prev.unwrap_or(self.head);
+    match next {
+      Some(next) => lock.write_lock_all(&[prev, next]),
+      None => lock.write_lock_all(&[prev]),
+    }?;
 
-      return Some((
-        prev_node.map(|p| (prev.unwrap(), p)),
-        next_node.map(|n| (next.unwrap(), n)),
-        head_node,
-        lock,
-      ));
-    }
+    Ok(Some((prev, next)))
   }
 
   pub fn contains(&self, value: T) -> Option<()> {
-    let mut lock = unsafe { (*self.thread).session() };
-    let (_, head) = self.find(&mut lock, value);
+    let mut lock = unsafe { (*self.thread.get()).read_session() };
+    let (_, head) = self.find(&lock, value);
     head.and_then(|head_ref| {
-      if unsafe { *lock.read_lock(head_ref) }.value == value {
+      if lock.read_lock(head_ref).value == value {
         Some(())
       } else {
         None
@@ -138,8 +115,8 @@ impl<T: RluBounds + PartialEq + PartialOrd + Copy> RluList<T> {
   }
 
   pub fn len(&self) -> usize {
-    let mut lock = unsafe { (*self.thread).session() };
-    let mut cur = &unsafe { (*lock.read_lock(self.head)).next };
+    let mut lock = unsafe { (*self.thread.get()).read_session() };
+    let mut cur = lock.read_lock(self.head).next;
     let mut i = 0;
 
     loop {
@@ -148,9 +125,9 @@ impl<T: RluBounds + PartialEq + PartialOrd + Copy> RluList<T> {
           break;
         }
         Some(cur_ref) => {
-          let node = lock.read_lock(*cur_ref);
+          let node = lock.read_lock(cur_ref);
           i += 1;
-          cur = unsafe { &(*node).next };
+          cur = node.next;
         }
       };
     }
@@ -158,74 +135,57 @@ impl<T: RluBounds + PartialEq + PartialOrd + Copy> RluList<T> {
     return i;
   }
 
-  pub fn insert(&mut self, value: T) -> Option<()> {
-    let (prev_opt, next_opt, head_node, mut lock) =
-      self.find_lock(value, true)?;
-
-    let new = self.rlu.alloc(RluListNode { value, next: None });
-
-    let new = if let Some((next, next_node)) = next_opt {
-      let new_ptr = lock.write_lock(new).expect("Try lock failed");
-      unsafe {
-        (*new_ptr).next = Some(next);
-      }
-      new
-    } else {
-      self.rlu.alloc(RluListNode { value, next: None })
-    };
+  pub fn insert(&mut self, value: T) -> Result<bool, TransactionError> {
+    let thread = unsafe { &mut *self.thread.get() };
+    thread.transaction(|lock| {
+      let (prev, next) = match self.find_lock(lock, value, true)? {
+        Some(found) => found,
+        None => {
+          return Ok(false);
+        }
+      };
 
-    if let Some((prev, prev_node)) = prev_opt {
-      unsafe {
-        (*prev_node).next = Some(new);
-      }
-    } else {
-      unsafe {
-        (*head_node.unwrap()).next = Some(new);
-      }
-    }
+      let new = lock.alloc(RluListNode { value, next });
+      lock.write_lock(prev).expect("Relock failed").next = Some(new);
 
-    Some(())
+      Ok(true)
+    })
   }
 
-  pub fn delete(&mut self, value: T) -> Option<()> {
-    let (prev_opt, next_opt, head_node, mut lock) =
-      self.find_lock(value, false)?;
-
-    if let Some((prev, prev_node)) = prev_opt {
-      if let Some((_, next_node)) = next_opt {
-        if let Some(next2) = unsafe { (*next_node).next } {
-          unsafe {
-            (*prev_node).next = Some(next2);
-          }
-        } else {
-          unsafe {
-            (*prev_node).next = None;
-          }
-        }
-      } else {
-        unsafe {
-          (*prev_node).next = None;
+  pub fn delete(&mut self, value: T) -> Result<bool, TransactionError> {
+    let thread = unsafe { &mut *self.thread.get() };
+    thread.transaction(|lock| {
+      let (prev, next) = match self.find_lock(lock, value, false)? {
+        Some(found) => found,
+        None => {
+          return Ok(false);
         }
-      }
-    } else {
-      unsafe {
-        (*head_node.unwrap()).next =
-          next_opt.and_then(|(_, next_node)| (*next_node).next);
-      }
-    }
+      };
 
-    if let Some((next, _)) = next_opt {
-      unsafe {
-        (*self.thread).free(next);
+      let next2 = next.and_then(|next| lock.read_lock(next).next);
+      lock.write_lock(prev).expect("Relock failed").next = next2;
+      if let Some(next) = next {
+        lock.free(next);
       }
-    }
 
-    Some(())
+      Ok(true)
+    })
+  }
+
+  // Makes this handle's deferred writes visible to other threads, see
+  // `RluConfig::defer_writeback`.
+  pub fn flush(&mut self) {
+    unsafe { (*self.thread.get()).flush() };
+  }
+
+  #[cfg(feature = "stats")]
+  pub fn stats(&self) -> RluStats {
+    self.rlu.stats()
   }
 
   pub fn to_string(&self) -> String {
-    let mut lock = unsafe { (*self.thread).session() };
-    let mut cur = &unsafe { (*lock.read_lock(self.head)).next };
+    let mut lock = unsafe { (*self.thread.get()).read_session() };
+    let mut cur = lock.read_lock(self.head).next;
     let mut s = String::new();
 
     loop {
@@ -234,9 +194,9 @@ impl<T: RluBounds + PartialEq + PartialOrd + Copy> RluList<T> {
           break;
         }
         Some(cur_ref) => {
-          let node = lock.read_lock(*cur_ref);
-          s += &format!(" --> {:?}", unsafe { *node });
-          cur = unsafe { &(*node).next };
+          let node = lock.read_lock(cur_ref);
+          s += &format!(" --> {:?}", *node);
+          cur = node.next;
         }
       };
     }
@@ -249,7 +209,7 @@ impl<T: RluBounds> Clone for RluList<T> {
   fn clone(&self) -> Self {
     RluList {
       head: self.head,
-      thread: self.rlu.thread(),
+      thread: UnsafeCell::new(self.rlu.thread()),
       rlu: self.rlu.clone(),
     }
   }
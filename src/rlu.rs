@@ -1,25 +1,52 @@
 #![allow(dead_code, unused_variables)]
 
-use std::fmt::Debug;
-use std::mem;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ops::{Add, Deref, DerefMut};
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::usize;
 
-const RLU_MAX_LOG_SIZE: usize = 128;
 const RLU_MAX_THREADS: usize = 32;
-const RLU_MAX_FREE_NODES: usize = 100;
+const RLU_LOG_CAPACITY: usize = 128;
+const RLU_FREE_CAPACITY: usize = 100;
+const RLU_PENDING_CLOCK: usize = usize::MAX;
 
 pub struct ObjOriginal<T> {
   copy: AtomicPtr<ObjCopy<T>>,
+  // Committed versions, newest first. Always empty unless the domain runs in
+  // multi-version mode, in which case `data` is older than all of them.
+  versions: AtomicPtr<ObjVersion<T>>,
+  // Clock of the last commit written back into `data`
+  write_clock: AtomicUsize,
+  // Set once a commit that frees the object has been published
+  retired: AtomicBool,
   data: T,
 }
 
+// `commit_clock` is `RLU_PENDING_CLOCK` while the writer that pushed the
+// version is still getting its write clock.
+struct ObjVersion<T> {
+  commit_clock: AtomicUsize,
+  data: T,
+  older: AtomicPtr<ObjVersion<T>>,
+}
+
+// `data` is only initialized while the copy is part of its log, see
+// `WriteLog`.
 pub struct ObjCopy<T> {
   thread_id: usize,
+  // Run counter of the owner's session that last locked the copy
+  session: usize,
   original: RluObject<T>,
-  data: T,
+  data: MaybeUninit<T>,
 }
 
 #[derive(Debug)]
@@ -32,7 +59,37 @@ impl<T> Clone for RluObject<T> {
 }
 impl<T> Copy for RluObject<T> {}
 
+// Objects are compared by identity, never by their contents.
+impl<T> PartialEq for RluObject<T> {
+  fn eq(&self, other: &Self) -> bool {
+    self.0 == other.0
+  }
+}
+impl<T> Eq for RluObject<T> {}
+
+impl<T> Hash for RluObject<T> {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.0.hash(state);
+  }
+}
+
 impl<T> RluObject<T> {
+  fn new(data: T) -> RluObject<T> {
+    RluObject(Box::into_raw(Box::new(ObjOriginal {
+      copy: AtomicPtr::new(ptr::null_mut()),
+      versions: AtomicPtr::new(ptr::null_mut()),
+      write_clock: AtomicUsize::new(0),
+      retired: AtomicBool::new(false),
+      data,
+    })))
+  }
+
+  // Objects are at least word aligned, so the low bit is free to mark a pin
+  // whose object has been reclaimed.
+  fn retired_pin(&self) -> *mut ObjOriginal<T> {
+    (self.0 as usize | 1) as *mut ObjOriginal<T>
+  }
+
   fn deref(&self) -> &ObjOriginal<T> {
     unsafe { &*self.0 }
   }
@@ -42,28 +99,206 @@ impl<T> RluObject<T> {
   }
 }
 
+// Entries are boxed individually so that growing the log never moves a copy
+// that an `ObjOriginal` (and possibly another thread) is pointing at. The
+// first `num_entries` copies hold a live `T`; the rest are spare slots whose
+// data has already been dropped or moved into its original.
 struct WriteLog<T> {
-  entries: [ObjCopy<T>; RLU_MAX_LOG_SIZE],
+  entries: Vec<Box<ObjCopy<T>>>,
   num_entries: usize,
 }
 
 pub struct RluThread<T> {
   logs: [WriteLog<T>; 2],
+  log_limit: Option<usize>,
+  retry_policy: RluRetryPolicy,
   current_log: usize,
   is_writer: bool,
   write_clock: usize,
   local_clock: AtomicUsize,
   run_counter: AtomicUsize,
+  waiters: Mutex<Vec<thread::Thread>>,
+  has_waiters: AtomicBool,
   thread_id: usize,
   global: *const Rlu<T>,
-  free_list: [RluObject<T>; RLU_MAX_FREE_NODES],
-  num_free: usize,
+  #[cfg(feature = "stats")]
+  stats: RluThreadStats,
+  free_list: Vec<RluObject<T>>,
+  deferred: Vec<Box<dyn FnOnce() + Send>>,
+  // Objects allocated by the running session, reclaimed if it aborts
+  allocs: Vec<RluObject<T>>,
+  // Objects retired by the running session, forgotten if it aborts
+  frees: Vec<RluObject<T>>,
+  // Log entries left over from earlier sessions in deferred mode, which an
+  // abort must keep
+  session_entries: usize,
+  // Previous data of leftover copies the running session has locked again
+  undo: Vec<(*mut ObjCopy<T>, T)>,
+  // Set by writers that found one of our deferred copies in their way
+  sync_requested: AtomicBool,
+  // Objects the running session has read, if it is serializable
+  serializable: bool,
+  read_set: RefCell<Vec<RluObject<T>>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RluStats {
+  pub sessions: usize,
+  pub commits: usize,
+  pub aborts: usize,
+  pub write_lock_conflicts: usize,
+  pub log_entries: usize,
+  // Number of readers a writer actually had to wait on in `synchronize`
+  pub synchronize_waits: usize,
+  pub frees: usize,
+}
+
+// Only the owning thread updates these, but `Rlu::stats` reads them from
+// other threads.
+#[cfg(feature = "stats")]
+#[derive(Default)]
+struct RluThreadStats {
+  sessions: AtomicUsize,
+  commits: AtomicUsize,
+  aborts: AtomicUsize,
+  write_lock_conflicts: AtomicUsize,
+  log_entries: AtomicUsize,
+  synchronize_waits: AtomicUsize,
+  frees: AtomicUsize,
+}
+
+// Per-thread state is boxed and only allocated the first time a slot is
+// claimed, so an idle domain costs a pointer per slot regardless of `T`.
+struct RluThreadSlot<T> {
+  thread: AtomicPtr<RluThread<T>>,
+  active: AtomicBool,
+  // Live objects allocated by this thread's sessions
+  objects: Mutex<HashSet<RluObject<T>>>,
+  // Object `write_lock_wait` is waiting on outside of a session. Reclaiming
+  // it tags the pointer and leaves freeing it to the waiter.
+  pinned: AtomicPtr<ObjOriginal<T>>,
 }
 
 pub struct Rlu<T> {
   global_clock: AtomicUsize,
-  threads: [RluThread<T>; RLU_MAX_THREADS],
+  threads: Box<[RluThreadSlot<T>]>,
   num_threads: AtomicUsize,
+  // Live objects allocated straight from the domain. Together with the
+  // per-thread registries this lets dropping the domain reclaim whatever its
+  // users never freed.
+  objects: Mutex<HashSet<RluObject<T>>>,
+  config: RluConfig,
+}
+
+#[derive(Clone)]
+pub struct RluConfig {
+  // Number of threads that may be registered with the domain at once
+  pub max_threads: usize,
+  // Number of write-log entries each thread reserves up front
+  pub log_capacity: usize,
+  // Hard cap on write-log entries per session, see `set_log_limit`
+  pub log_limit: Option<usize>,
+  // Number of deferred frees each thread reserves up front
+  pub free_capacity: usize,
+  // How `RluThread::transaction` retries conflicting sessions
+  pub retry_policy: RluRetryPolicy,
+  // How writers wait for readers during `synchronize`
+  pub wait_strategy: RluWaitStrategy,
+  // Enables multi-version mode: commits push a new version instead of
+  // waiting for readers, and each object keeps at most this many of them
+  pub max_versions: Option<usize>,
+  // Keeps each thread's write log across sessions and only writes it back
+  // once it fills up, another writer needs one of its objects, or on
+  // `RluThread::flush`. Not supported together with `max_versions`.
+  pub defer_writeback: bool,
+  // Receives an `RluEvent` for everything the domain does
+  #[cfg(feature = "observer")]
+  pub observer: Option<Arc<dyn RluObserver>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RluEvent {
+  SessionBegin {
+    thread_id: usize,
+    local_clock: usize,
+  },
+  SessionEnd {
+    thread_id: usize,
+    aborted: bool,
+  },
+  WriteLock {
+    thread_id: usize,
+    result: Result<(), WriteLockError>,
+  },
+  Commit {
+    thread_id: usize,
+    write_clock: usize,
+  },
+  // A synchronizing thread (`None` for `Rlu::synchronize`) had to wait for
+  // `reader_id` to leave its session
+  SynchronizeWait {
+    thread_id: Option<usize>,
+    reader_id: usize,
+  },
+}
+
+// Events are delivered synchronously on the thread that caused them, so
+// implementations should be cheap and must not block on the domain.
+#[cfg(feature = "observer")]
+pub trait RluObserver: Send + Sync {
+  fn on_event(&self, event: RluEvent);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RluWaitStrategy {
+  Spin,
+  Yield,
+  // Sleep until the reader's session ends and it wakes us up
+  Park,
+  // Spin `spins` times, then yield `yields` times, then park
+  Adaptive { spins: usize, yields: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteLockError {
+  // Another thread has its own copy of the object installed
+  HeldBy { thread_id: usize },
+  // The session already holds as many locks as the log limit allows
+  LogFull,
+  // A version newer than the session's snapshot has already been committed,
+  // only returned in multi-version mode
+  Outdated,
+  // `write_lock_wait` gave up waiting for the owner to release the object
+  Timeout,
+  // The object was freed while `write_lock_wait` waited for it
+  Freed,
+}
+
+// Returned from a transaction body to abort the session and run it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RluRetry;
+
+// Error type of a transaction body. Only `Retry` and contention on a write
+// lock run the body again, other write lock errors are returned to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionError {
+  Retry,
+  WriteLock(WriteLockError),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RluBackoff {
+  Spin,
+  Yield,
+  // Sleep for `initial`, doubling on every attempt up to `max`
+  Sleep { initial: Duration, max: Duration },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RluRetryPolicy {
+  pub backoff: RluBackoff,
+  // Give up with the last attempt's error after this many aborted attempts
+  pub max_attempts: Option<usize>,
 }
 
 unsafe impl<T> Send for RluObject<T> {}
@@ -72,106 +307,747 @@ unsafe impl<T> Sync for RluObject<T> {}
 unsafe impl<T> Send for RluThread<T> {}
 unsafe impl<T> Sync for RluThread<T> {}
 
+pub struct RluThreadHandle<T> {
+  rlu: Arc<Rlu<T>>,
+  thread_id: usize,
+}
+
 pub struct RluSession<'a, T: RluBounds> {
   t: &'a mut RluThread<T>,
   abort: bool,
+  // Whether the thread was already unwinding when the session began
+  panicking: bool,
+}
+
+// A session that can only read, so it never commits or takes the writer path.
+pub struct RluReadSession<'a, T: RluBounds> {
+  t: &'a mut RluThread<T>,
+}
+
+// Implemented by both session types, for code that only needs to read.
+pub trait RluRead<T> {
+  fn read_lock(&self, obj: RluObject<T>) -> RluReadGuard<'_, T>;
+}
+
+pub struct RluReadGuard<'s, T> {
+  data: &'s T,
+}
+
+pub struct RluWriteGuard<'s, T> {
+  data: &'s mut T,
 }
 
 pub trait RluBounds: Clone + Debug {}
 impl<T: Clone + Debug> RluBounds for T {}
 
+impl Default for RluConfig {
+  fn default() -> RluConfig {
+    RluConfig {
+      max_threads: RLU_MAX_THREADS,
+      log_capacity: RLU_LOG_CAPACITY,
+      log_limit: None,
+      free_capacity: RLU_FREE_CAPACITY,
+      retry_policy: RluRetryPolicy::default(),
+      wait_strategy: RluWaitStrategy::Yield,
+      max_versions: None,
+      defer_writeback: false,
+      #[cfg(feature = "observer")]
+      observer: None,
+    }
+  }
+}
+
+impl Default for RluRetryPolicy {
+  fn default() -> RluRetryPolicy {
+    RluRetryPolicy {
+      backoff: RluBackoff::Yield,
+      max_attempts: None,
+    }
+  }
+}
+
+impl Add for RluStats {
+  type Output = RluStats;
+
+  fn add(self, other: RluStats) -> RluStats {
+    RluStats {
+      sessions: self.sessions + other.sessions,
+      commits: self.commits + other.commits,
+      aborts: self.aborts + other.aborts,
+      write_lock_conflicts: self.write_lock_conflicts
+        + other.write_lock_conflicts,
+      log_entries: self.log_entries + other.log_entries,
+      synchronize_waits: self.synchronize_waits + other.synchronize_waits,
+      frees: self.frees + other.frees,
+    }
+  }
+}
+
+#[cfg(feature = "stats")]
+impl RluThreadStats {
+  fn load(&self) -> RluStats {
+    RluStats {
+      sessions: self.sessions.load(Ordering::Relaxed),
+      commits: self.commits.load(Ordering::Relaxed),
+      aborts: self.aborts.load(Ordering::Relaxed),
+      write_lock_conflicts: self.write_lock_conflicts.load(Ordering::Relaxed),
+      log_entries: self.log_entries.load(Ordering::Relaxed),
+      synchronize_waits: self.synchronize_waits.load(Ordering::Relaxed),
+      frees: self.frees.load(Ordering::Relaxed),
+    }
+  }
+}
+
+impl fmt::Display for WriteLockError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      WriteLockError::HeldBy { thread_id } => {
+        write!(f, "object is locked by thread {}", thread_id)
+      }
+      WriteLockError::LogFull => write!(f, "write log is full"),
+      WriteLockError::Outdated => {
+        write!(f, "object was updated after the session began")
+      }
+      WriteLockError::Timeout => {
+        write!(f, "timed out waiting for the object to be unlocked")
+      }
+      WriteLockError::Freed => {
+        write!(f, "object was freed while waiting for it")
+      }
+    }
+  }
+}
+
+impl Error for WriteLockError {}
+
+impl WriteLockError {
+  // Whether running the session again at a fresh snapshot can get past this
+  pub fn is_retryable(&self) -> bool {
+    match self {
+      WriteLockError::HeldBy { .. }
+      | WriteLockError::Outdated
+      | WriteLockError::Freed => true,
+      WriteLockError::LogFull | WriteLockError::Timeout => false,
+    }
+  }
+}
+
+impl TransactionError {
+  fn is_retryable(&self) -> bool {
+    match self {
+      TransactionError::Retry => true,
+      TransactionError::WriteLock(err) => err.is_retryable(),
+    }
+  }
+}
+
+impl From<RluRetry> for TransactionError {
+  fn from(_: RluRetry) -> TransactionError {
+    TransactionError::Retry
+  }
+}
+
+impl From<WriteLockError> for TransactionError {
+  fn from(err: WriteLockError) -> TransactionError {
+    TransactionError::WriteLock(err)
+  }
+}
+
+impl fmt::Display for TransactionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TransactionError::Retry => write!(f, "transaction gave up retrying"),
+      TransactionError::WriteLock(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+impl Error for TransactionError {}
+
+impl RluBackoff {
+  fn wait(&self, attempt: usize) {
+    match *self {
+      RluBackoff::Spin => std::hint::spin_loop(),
+      RluBackoff::Yield => thread::yield_now(),
+      RluBackoff::Sleep { initial, max } => {
+        let factor = 1 << attempt.saturating_sub(1).min(16);
+        thread::sleep((initial * factor).min(max));
+      }
+    }
+  }
+}
+
+impl<T> ObjOriginal<T> {
+  // Newest data committed at or before `clock`.
+  fn version_at(&self, clock: usize) -> *const T {
+    let mut version = self.versions.load(Ordering::SeqCst);
+    while let Some(v) = unsafe { version.as_ref() } {
+      let mut commit_clock = v.commit_clock.load(Ordering::SeqCst);
+      while commit_clock == RLU_PENDING_CLOCK {
+        thread::yield_now();
+        commit_clock = v.commit_clock.load(Ordering::SeqCst);
+      }
+
+      if commit_clock <= clock {
+        return &v.data;
+      }
+      version = v.older.load(Ordering::SeqCst);
+    }
+
+    &self.data
+  }
+
+  fn is_locked_by(&self, thread_id: usize) -> bool {
+    match unsafe { self.copy.load(Ordering::SeqCst).as_ref() } {
+      Some(copy) => copy.thread_id == thread_id,
+      None => false,
+    }
+  }
+
+  // Clock of the newest commit to this object that has become visible.
+  fn newest_clock(&self) -> usize {
+    match unsafe { self.versions.load(Ordering::SeqCst).as_ref() } {
+      Some(newest) => newest.commit_clock.load(Ordering::SeqCst),
+      None => self.write_clock.load(Ordering::SeqCst),
+    }
+  }
+
+  // Drops every version older than the newest one visible at `min_clock`,
+  // since no session can look past that one. Only the thread holding the
+  // object's lock may call this. Returns the remaining chain length.
+  fn trim_versions(&self, min_clock: usize) -> usize {
+    let mut len = 0;
+    let mut version = self.versions.load(Ordering::SeqCst);
+    while let Some(v) = unsafe { version.as_ref() } {
+      len += 1;
+      if v.commit_clock.load(Ordering::SeqCst) <= min_clock {
+        ObjVersion::free_chain(v.older.swap(ptr::null_mut(), Ordering::SeqCst));
+        break;
+      }
+      version = v.older.load(Ordering::SeqCst);
+    }
+
+    len
+  }
+}
+
+impl<T> Drop for ObjOriginal<T> {
+  fn drop(&mut self) {
+    ObjVersion::free_chain(*self.versions.get_mut());
+  }
+}
+
+impl<T> ObjVersion<T> {
+  fn free_chain(mut version: *mut ObjVersion<T>) {
+    while !version.is_null() {
+      let v = unsafe { Box::from_raw(version) };
+      version = v.older.load(Ordering::SeqCst);
+    }
+  }
+}
+
 impl<T> WriteLog<T> {
-  fn next_entry(&mut self) -> &mut ObjCopy<T> {
+  fn new(capacity: usize) -> WriteLog<T> {
+    WriteLog {
+      entries: Vec::with_capacity(capacity),
+      num_entries: 0,
+    }
+  }
+
+  fn next_entry(&mut self, copy: ObjCopy<T>) -> &mut ObjCopy<T> {
     let i = self.num_entries;
     self.num_entries += 1;
 
-    if cfg!(debug_assertions) {
-      assert!(self.num_entries < RLU_MAX_LOG_SIZE)
+    if i == self.entries.len() {
+      self.entries.push(Box::new(copy));
+    } else {
+      // The slot's previous payload was already dropped or written back
+      *self.entries[i] = copy;
     }
 
-    unsafe { self.entries.get_unchecked_mut(i) }
+    &mut self.entries[i]
+  }
+
+  fn pop_entry(&mut self) {
+    self.num_entries -= 1;
+    unsafe { self.entries[self.num_entries].data.assume_init_drop() };
+  }
+
+  // Drops every payload in the log. Callers must make sure no other thread
+  // can still be reading them.
+  fn clear(&mut self) {
+    while self.num_entries > 0 {
+      self.pop_entry();
+    }
   }
 }
 
-impl<T: RluBounds> Rlu<T> {
+impl<T> Drop for WriteLog<T> {
+  fn drop(&mut self) {
+    self.clear();
+  }
+}
+
+macro_rules! event {
+  ($rlu:expr, $event:expr) => {
+    #[cfg(feature = "observer")]
+    {
+      if let Some(observer) = &$rlu.config.observer {
+        observer.on_event($event);
+      }
+    }
+  };
+}
+
+macro_rules! stat {
+  ($thread:expr, $counter:ident) => {
+    stat!($thread, $counter, 1)
+  };
+  ($thread:expr, $counter:ident, $n:expr) => {
+    #[cfg(feature = "stats")]
+    $thread.stats.$counter.fetch_add($n, Ordering::Relaxed);
+  };
+}
+
+impl<T> Rlu<T> {
   pub fn new() -> Rlu<T> {
+    Rlu::with_config(RluConfig::default())
+  }
+
+  pub fn with_config(config: RluConfig) -> Rlu<T> {
+    assert!(
+      !config.defer_writeback || config.max_versions.is_none(),
+      "Deferred writeback does not support multi-version mode"
+    );
     Rlu {
       global_clock: AtomicUsize::new(0),
       num_threads: AtomicUsize::new(0),
-      threads: unsafe { mem::uninitialized() },
+      threads: (0..config.max_threads)
+        .map(|_| RluThreadSlot {
+          thread: AtomicPtr::new(ptr::null_mut()),
+          active: AtomicBool::new(false),
+          objects: Mutex::new(HashSet::new()),
+          pinned: AtomicPtr::new(ptr::null_mut()),
+        })
+        .collect(),
+      objects: Mutex::new(HashSet::new()),
+      config,
+    }
+  }
+
+  pub fn thread(self: &Arc<Self>) -> RluThreadHandle<T> {
+    // Claim the first vacant slot. Thread state is never torn down once
+    // allocated, so a reused slot keeps its logs and run counter and
+    // concurrent synchronizers never observe a half-initialized thread.
+    let thread_id = (0..self.threads.len())
+      .find(|&i| {
+        self.threads[i]
+          .active
+          .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+          .is_ok()
+      })
+      .expect("Exceeded maximum number of RLU threads");
+    self.num_threads.fetch_max(thread_id + 1, Ordering::SeqCst);
+
+    let slot = &self.threads[thread_id];
+    if slot.thread.load(Ordering::SeqCst).is_null() {
+      let thread = Box::new(RluThread::new(thread_id, &self.config));
+      slot.thread.store(Box::into_raw(thread), Ordering::SeqCst);
+    }
+
+    let thread = unsafe { &mut *self.get_thread(thread_id) };
+    thread.global = &**self as *const Rlu<T>;
+    thread.is_writer = false;
+    thread.log_limit = self.config.log_limit;
+    thread.retry_policy = self.config.retry_policy;
+
+    RluThreadHandle {
+      rlu: self.clone(),
+      thread_id,
     }
   }
 
-  pub fn thread(&self) -> &mut RluThread<T> {
-    let thread_id = self.num_threads.fetch_add(1, Ordering::SeqCst);
-    let thread: *mut RluThread<T> =
-      &self.threads[thread_id] as *const RluThread<T> as *mut RluThread<T>;
-    let thread: &mut RluThread<T> = unsafe { &mut *thread };
-    *thread = RluThread::new();
-    thread.thread_id = thread_id;
-    thread.global = self as *const Rlu<T>;
-    return thread;
+  // Blocks until every session that was running when this was called has
+  // ended. Must not be called from inside a session on the calling thread.
+  pub fn synchronize(&self) {
+    self.wait_for_readers(None, usize::MAX);
+  }
+
+  // Totals over every thread that has ever registered with the domain.
+  #[cfg(feature = "stats")]
+  pub fn stats(&self) -> RluStats {
+    (0..self.num_threads.load(Ordering::SeqCst))
+      .filter_map(|i| unsafe { self.get_thread(i).as_ref() })
+      .fold(RluStats::default(), |acc, thread| acc + thread.stats())
+  }
+
+  // Waits for sessions other than `skip` that may still observe state older
+  // than `write_clock`, returning how many sessions had to be waited on.
+  fn wait_for_readers(&self, skip: Option<usize>, write_clock: usize) -> usize {
+    let mut waits = 0;
+    let num_threads = self.num_threads.load(Ordering::SeqCst);
+    let run_counts: Vec<usize> = (0..num_threads)
+      .map(|i| match unsafe { self.get_thread(i).as_ref() } {
+        Some(thread) => thread.run_counter.load(Ordering::SeqCst),
+        None => 0,
+      })
+      .collect();
+
+    for i in 0..num_threads {
+      if Some(i) == skip || !self.threads[i].active.load(Ordering::SeqCst) {
+        continue;
+      }
+
+      let thread = match unsafe { self.get_thread(i).as_ref() } {
+        Some(thread) => thread,
+        None => continue,
+      };
+
+      let done = || {
+        run_counts[i] % 2 == 0
+          || thread.run_counter.load(Ordering::SeqCst) != run_counts[i]
+          || write_clock <= thread.local_clock.load(Ordering::SeqCst)
+      };
+
+      if !done() {
+        waits += 1;
+        event!(
+          self,
+          RluEvent::SynchronizeWait {
+            thread_id: skip,
+            reader_id: i,
+          }
+        );
+      }
+
+      let mut attempt = 0;
+      while !done() {
+        match self.config.wait_strategy {
+          RluWaitStrategy::Spin => std::hint::spin_loop(),
+          RluWaitStrategy::Yield => thread::yield_now(),
+          RluWaitStrategy::Park => thread.park_until(&done, None),
+          RluWaitStrategy::Adaptive { spins, yields } => {
+            if attempt < spins {
+              std::hint::spin_loop();
+            } else if attempt < spins + yields {
+              thread::yield_now();
+            } else {
+              thread.park_until(&done, None);
+            }
+          }
+        }
+
+        attempt += 1;
+      }
+    }
+
+    waits
+  }
+
+  // Oldest snapshot that a running or future session may read at. The
+  // global clock is loaded first, so a session that starts while we scan
+  // can only read at that clock or later.
+  fn min_reader_clock(&self) -> usize {
+    let mut min_clock = self.global_clock.load(Ordering::SeqCst);
+    for i in 0..self.num_threads.load(Ordering::SeqCst) {
+      if let Some(thread) = unsafe { self.get_thread(i).as_ref() } {
+        if thread.run_counter.load(Ordering::SeqCst) % 2 == 1 {
+          min_clock = min_clock.min(thread.local_clock.load(Ordering::SeqCst));
+        }
+      }
+    }
+
+    min_clock
+  }
+
+  // Asks a deferred writer holding an object we want to write it back.
+  fn request_sync(&self, thread_id: usize) {
+    if self.config.defer_writeback {
+      let thread = unsafe { &*self.get_thread(thread_id) };
+      thread.sync_requested.store(true, Ordering::SeqCst);
+    }
   }
 
   fn get_thread(&self, index: usize) -> *mut RluThread<T> {
-    (unsafe { self.threads.get_unchecked(index) }) as *const RluThread<T>
-      as *mut RluThread<T>
+    self.threads[index].thread.load(Ordering::SeqCst)
   }
 
+  // Objects allocated here share one registry, so code that allocates
+  // from many threads at once should use `RluSession::alloc` instead.
   pub fn alloc(&self, data: T) -> RluObject<T> {
-    RluObject(Box::into_raw(Box::new(ObjOriginal {
-      copy: AtomicPtr::new(ptr::null_mut()),
-      data,
-    })))
+    let obj = RluObject::new(data);
+    self.objects.lock().unwrap().insert(obj);
+    obj
+  }
+
+  fn alloc_on(&self, thread_id: usize, data: T) -> RluObject<T> {
+    let obj = RluObject::new(data);
+    self.threads[thread_id].objects.lock().unwrap().insert(obj);
+    obj
+  }
+
+  pub fn num_objects(&self) -> usize {
+    self.objects.lock().unwrap().len()
+      + self
+        .threads
+        .iter()
+        .map(|slot| slot.objects.lock().unwrap().len())
+        .sum::<usize>()
+  }
+
+  fn reclaim(&self, thread_id: usize, obj: RluObject<T>) {
+    // Objects are mostly freed by the thread that allocated them, so the
+    // other registries are only searched when that one misses
+    let registered =
+      self.threads[thread_id].objects.lock().unwrap().remove(&obj)
+        || self.objects.lock().unwrap().remove(&obj)
+        || (0..self.num_threads.load(Ordering::SeqCst))
+          .filter(|&i| i != thread_id)
+          .any(|i| self.threads[i].objects.lock().unwrap().remove(&obj));
+    assert!(
+      registered,
+      "Freed an object that is not live in this RLU domain"
+    );
+
+    let handed_over = (0..self.num_threads.load(Ordering::SeqCst)).any(|i| {
+      self.threads[i]
+        .pinned
+        .compare_exchange(
+          obj.0,
+          obj.retired_pin(),
+          Ordering::SeqCst,
+          Ordering::SeqCst,
+        )
+        .is_ok()
+    });
+    if !handed_over {
+      drop(unsafe { Box::from_raw(obj.0) });
+    }
+  }
+
+  fn unpin(&self, thread_id: usize, obj: RluObject<T>) {
+    let pinned = self.threads[thread_id]
+      .pinned
+      .swap(ptr::null_mut(), Ordering::SeqCst);
+    if pinned == obj.retired_pin() {
+      drop(unsafe { Box::from_raw(obj.0) });
+    }
   }
 }
 
-macro_rules! log {
-  ($self:expr, $e:expr) => {
-    if cfg!(debug_assertions) {
-      let s: String = $e.into();
-      println!("Thread {}: {}", $self.thread_id, s);
+impl<T> Drop for Rlu<T> {
+  fn drop(&mut self) {
+    // Every handle is gone, so no session is running and nothing can still
+    // be reading the objects. Pending frees are still registered and are
+    // reclaimed along with everything else below.
+    let mut threads: Vec<_> = self
+      .threads
+      .iter()
+      .map(|slot| slot.thread.load(Ordering::SeqCst))
+      .filter(|thread| !thread.is_null())
+      .map(|thread| unsafe { Box::from_raw(thread) })
+      .collect();
+
+    for thread in threads.iter_mut() {
+      for f in thread.deferred.drain(..) {
+        f();
+      }
     }
-  };
+
+    let registries = self
+      .threads
+      .iter_mut()
+      .map(|slot| slot.objects.get_mut().unwrap())
+      .chain(Some(self.objects.get_mut().unwrap()));
+    for objects in registries {
+      for obj in objects.drain() {
+        drop(unsafe { Box::from_raw(obj.0) });
+      }
+    }
+  }
+}
+
+impl<T: RluBounds> Deref for RluThreadHandle<T> {
+  type Target = RluThread<T>;
+
+  fn deref(&self) -> &RluThread<T> {
+    unsafe { &*self.rlu.get_thread(self.thread_id) }
+  }
+}
+
+impl<T: RluBounds> DerefMut for RluThreadHandle<T> {
+  fn deref_mut(&mut self) -> &mut RluThread<T> {
+    unsafe { &mut *self.rlu.get_thread(self.thread_id) }
+  }
+}
+
+impl<T> Drop for RluThreadHandle<T> {
+  fn drop(&mut self) {
+    // Deferred copies would otherwise stay locked and invisible for good,
+    // and queued callbacks would wait for the slot's next user
+    let thread = unsafe { &mut *self.rlu.get_thread(self.thread_id) };
+    thread.flush();
+    if !thread.deferred.is_empty() {
+      thread.wait_for_readers();
+    }
+    self.rlu.threads[self.thread_id]
+      .active
+      .store(false, Ordering::SeqCst);
+  }
 }
 
 impl<'a, T: RluBounds> RluSession<'a, T> {
-  pub fn read_lock(&mut self, obj: RluObject<T>) -> *const T {
-    log!(self.t, "dereference");
+  pub fn read_lock(&self, obj: RluObject<T>) -> RluReadGuard<'_, T> {
+    if self.t.serializable {
+      self.t.read_set.borrow_mut().push(obj);
+    }
+
+    RluReadGuard {
+      data: unsafe { &*self.t.dereference(obj) },
+    }
+  }
+
+  pub fn write_lock(
+    &mut self,
+    obj: RluObject<T>,
+  ) -> Result<RluWriteGuard<'_, T>, WriteLockError> {
+    self.lock(obj).map(|data| RluWriteGuard {
+      data: unsafe { &mut *data },
+    })
+  }
+
+  // Locks all of `objs` or none of them, returning guards in the same order.
+  // Objects are locked in address order so that writers locking overlapping
+  // sets always contend on the same object first. On failure only the locks
+  // taken by this call are released. Panics if an object is passed twice.
+  pub fn write_lock_all(
+    &mut self,
+    objs: &[RluObject<T>],
+  ) -> Result<Vec<RluWriteGuard<'_, T>>, WriteLockError> {
+    let mut order: Vec<usize> = (0..objs.len()).collect();
+    order.sort_by_key(|&i| objs[i].0);
+    if order.windows(2).any(|w| objs[w[0]] == objs[w[1]]) {
+      panic!("Object passed to write_lock_all twice");
+    }
+
+    let start = self.t.logs[self.t.current_log].num_entries;
+    let mut data = vec![ptr::null_mut(); objs.len()];
+    for i in order {
+      match self.lock(objs[i]) {
+        Ok(ptr) => data[i] = ptr,
+        Err(err) => {
+          self.t.unlock_entries_from(start);
+          return Err(err);
+        }
+      }
+    }
+
+    Ok(
+      data
+        .into_iter()
+        .map(|data| RluWriteGuard {
+          data: unsafe { &mut *data },
+        })
+        .collect(),
+    )
+  }
+
+  // Like `write_lock`, but instead of failing when another thread holds the
+  // object, aborts this session, waits up to `timeout` for the owner to
+  // release the object and tries again in a new session at a fresh snapshot.
+  // That restart is only done while the session hasn't locked, allocated,
+  // freed or (when serializable) read anything yet; otherwise the conflict is
+  // returned as is, like `write_lock` would. If the owner freed the object in
+  // the meantime this returns `Freed`. On `Timeout` the session is empty.
+  pub fn write_lock_wait(
+    &mut self,
+    obj: RluObject<T>,
+    timeout: Duration,
+  ) -> Result<RluWriteGuard<'_, T>, WriteLockError> {
     let global = unsafe { &*self.t.global };
-    let orig = obj.deref();
-    match unsafe { orig.copy.load(Ordering::SeqCst).as_ref() } {
-      None => &orig.data,
-      Some(copy) => {
-        if self.t.thread_id == copy.thread_id {
-          log!(
-            self.t,
-            format!("dereference self copy {:?} ({:p})", copy.data, &copy.data)
-          );
-          &copy.data
-        } else {
-          let thread = unsafe { &*global.get_thread(copy.thread_id) };
-          if thread.write_clock <= self.t.local_clock.load(Ordering::SeqCst) {
-            log!(self.t,
-                 format!("dereference other copy {:?} ({:p}), write clock {}, local clock {}", copy.data, &copy.data, thread.write_clock, self.t.local_clock.load(Ordering::SeqCst)));
-            &copy.data
-          } else {
-            log!(
-              self.t,
-              format!(
-                "dereferencing original {:?} ({:p})",
-                orig.data, &orig.data
-              )
-            );
-            &orig.data
-          }
+    let deadline = Instant::now() + timeout;
+    let mut pinned = false;
+    let result = loop {
+      let err = match self.lock(obj) {
+        Ok(data) => {
+          break Ok(data);
+        }
+        Err(err @ WriteLockError::HeldBy { .. })
+        | Err(err @ WriteLockError::Outdated) => err,
+        Err(err) => {
+          break Err(err);
         }
+      };
+
+      // Restarting would silently throw away what the session did so far
+      if !self.t.session_is_empty() {
+        break Err(err);
+      }
+      if Instant::now() >= deadline {
+        break Err(WriteLockError::Timeout);
+      }
+
+      // Outside of a session nothing keeps the owner from freeing `obj`, so
+      // it has to be pinned before we leave this one
+      if !pinned {
+        global.threads[self.t.thread_id]
+          .pinned
+          .store(obj.0, Ordering::SeqCst);
+        pinned = true;
       }
+
+      // The owner may be committing and waiting for our session to end, so
+      // we have to leave it before waiting on the owner. After `Outdated` a
+      // newer snapshot is all it takes.
+      let serializable = self.t.serializable;
+      self.t.abort();
+
+      let mut released = true;
+      if let WriteLockError::HeldBy { thread_id: owner } = err {
+        let thread = unsafe { &*global.get_thread(owner) };
+        let done = || !obj.deref().is_locked_by(owner);
+        while !done() && Instant::now() < deadline {
+          thread.park_until(&done, Some(deadline));
+        }
+        released = done();
+      }
+
+      self.t.start(serializable);
+      if !released {
+        break Err(WriteLockError::Timeout);
+      }
+      // Frees are published before their commit unlocks anything, and
+      // committing one waits for sessions that may have seen the object
+      if obj.deref().retired.load(Ordering::SeqCst) {
+        break Err(WriteLockError::Freed);
+      }
+    };
+
+    if pinned {
+      global.unpin(self.t.thread_id, obj);
     }
+    result.map(|data| RluWriteGuard {
+      data: unsafe { &mut *data },
+    })
+  }
+
+  fn lock(&mut self, obj: RluObject<T>) -> Result<*mut T, WriteLockError> {
+    let result = self.try_lock(obj);
+    event!(
+      unsafe { &*self.t.global },
+      RluEvent::WriteLock {
+        thread_id: self.t.thread_id,
+        result: result.map(|_| ()),
+      }
+    );
+
+    result
   }
 
-  pub fn write_lock(&mut self, mut obj: RluObject<T>) -> Option<*mut T> {
-    log!(self.t, format!("try_lock"));
+  fn try_lock(
+    &mut self,
+    mut obj: RluObject<T>,
+  ) -> Result<*mut T, WriteLockError> {
     let global = unsafe { &*self.t.global };
     self.t.is_writer = true;
 
@@ -179,48 +1055,163 @@ impl<'a, T: RluBounds> RluSession<'a, T> {
       unsafe { obj.deref_mut().copy.load(Ordering::SeqCst).as_mut() }
     {
       if self.t.thread_id == copy.thread_id {
-        log!(
-          self.t,
-          format!("locked existing copy {:?} ({:p})", copy.data, &copy.data)
-        );
-        return Some(&mut copy.data as *mut T);
+        // Only deferred mode keeps copies from earlier sessions around, and
+        // an abort has to restore them to what those sessions left behind
+        let session = self.t.run_counter.load(Ordering::SeqCst);
+        if copy.session != session {
+          let data = unsafe { copy.data.assume_init_ref() }.clone();
+          self.t.undo.push((copy as *mut ObjCopy<T>, data));
+          copy.session = session;
+        }
+        return Ok(copy.data.as_mut_ptr());
       } else {
-        return None;
+        stat!(self.t, write_lock_conflicts);
+        global.request_sync(copy.thread_id);
+        return Err(WriteLockError::HeldBy {
+          thread_id: copy.thread_id,
+        });
       }
     }
 
     let active_log = &mut self.t.logs[self.t.current_log];
-    let copy = active_log.next_entry();
-    copy.thread_id = self.t.thread_id;
-    copy.data = obj.deref().data.clone();
-    copy.original = obj;
-    let prev_ptr = obj.deref_mut().copy.compare_and_swap(
+    if let Some(limit) = self.t.log_limit {
+      if active_log.num_entries >= limit {
+        // Lets a deferred writer make room once this session is over
+        global.request_sync(self.t.thread_id);
+        return Err(WriteLockError::LogFull);
+      }
+    }
+
+    let copy = active_log.next_entry(ObjCopy {
+      thread_id: self.t.thread_id,
+      session: self.t.run_counter.load(Ordering::SeqCst),
+      original: obj,
+      data: MaybeUninit::new(
+        unsafe { &*obj.deref().version_at(usize::MAX) }.clone(),
+      ),
+    });
+    if let Err(prev_ptr) = obj.deref_mut().copy.compare_exchange(
       ptr::null_mut(),
       copy,
       Ordering::SeqCst,
-    );
-    if prev_ptr != ptr::null_mut() {
-      active_log.num_entries -= 1;
-      return None;
+      Ordering::SeqCst,
+    ) {
+      active_log.pop_entry();
+      stat!(self.t, write_lock_conflicts);
+      let thread_id = unsafe { (*prev_ptr).thread_id };
+      global.request_sync(thread_id);
+      return Err(WriteLockError::HeldBy { thread_id });
     }
 
-    log!(
-      self.t,
-      format!("locked new copy {:?} ({:p})", copy.data, &copy.data)
-    );
+    // Without a grace period between commits, another writer may have
+    // committed since our snapshot (or since we cloned the newest version).
+    // Its version is newer than `local_clock` and still at the head.
+    if global.config.max_versions.is_some() {
+      let newest = obj.deref().versions.load(Ordering::SeqCst);
+      if let Some(newest) = unsafe { newest.as_ref() } {
+        let local_clock = self.t.local_clock.load(Ordering::SeqCst);
+        if newest.commit_clock.load(Ordering::SeqCst) > local_clock {
+          obj.deref().copy.store(ptr::null_mut(), Ordering::SeqCst);
+          active_log.pop_entry();
+          stat!(self.t, write_lock_conflicts);
+          return Err(WriteLockError::Outdated);
+        }
+      }
+    }
 
-    Some(&mut copy.data as *mut T)
+    stat!(self.t, log_entries);
+    Ok(copy.data.as_mut_ptr())
+  }
+
+  // Allocates an object owned by this session. Until the session commits it
+  // is only reachable through the session's own writes, so an abort frees
+  // it again straight away.
+  pub fn alloc(&mut self, data: T) -> RluObject<T> {
+    let global = unsafe { &*self.t.global };
+    let obj = global.alloc_on(self.t.thread_id, data);
+    self.t.allocs.push(obj);
+    obj
+  }
+
+  // Retires an object once this session commits. Readers may still hold it,
+  // so it is only reclaimed after the commit's grace period, which means the
+  // session takes the writer path even if it locked nothing.
+  pub fn free(&mut self, obj: RluObject<T>) {
+    self.t.is_writer = true;
+    self.t.frees.push(obj);
   }
 
   pub fn abort(mut self) {
     self.abort = true;
   }
+
+  // Ends the session like dropping it would, but reports whether it actually
+  // committed. Only a serializable session can fail here, when validating
+  // its read set aborts it.
+  pub fn commit(self) -> Result<(), RluRetry> {
+    let mut session = ManuallyDrop::new(self);
+    if session.t.unlock() {
+      Ok(())
+    } else {
+      Err(RluRetry)
+    }
+  }
+}
+
+impl<'a, T: RluBounds> RluRead<T> for RluSession<'a, T> {
+  fn read_lock(&self, obj: RluObject<T>) -> RluReadGuard<'_, T> {
+    RluSession::read_lock(self, obj)
+  }
+}
+
+impl<'a, T: RluBounds> RluReadSession<'a, T> {
+  pub fn read_lock(&self, obj: RluObject<T>) -> RluReadGuard<'_, T> {
+    RluReadGuard {
+      data: unsafe { &*self.t.dereference(obj) },
+    }
+  }
+}
+
+impl<'a, T: RluBounds> RluRead<T> for RluReadSession<'a, T> {
+  fn read_lock(&self, obj: RluObject<T>) -> RluReadGuard<'_, T> {
+    RluReadSession::read_lock(self, obj)
+  }
+}
+
+impl<'a, T: RluBounds> Drop for RluReadSession<'a, T> {
+  fn drop(&mut self) {
+    self.t.exit(false);
+  }
+}
+
+impl<'s, T> Deref for RluReadGuard<'s, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    self.data
+  }
+}
+
+impl<'s, T> Deref for RluWriteGuard<'s, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    self.data
+  }
+}
+
+impl<'s, T> DerefMut for RluWriteGuard<'s, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    self.data
+  }
 }
 
 impl<'a, T: RluBounds> Drop for RluSession<'a, T> {
   fn drop(&mut self) {
-    log!(self.t, "drop");
-    if self.abort {
+    // A session dropped while unwinding may hold half-finished writes, so it
+    // must never reach the writeback path. One that began during the unwind
+    // (say in another value's `Drop`) ran to completion and still commits.
+    if self.abort || (thread::panicking() && !self.panicking) {
       self.t.abort();
     } else {
       self.t.unlock();
@@ -228,158 +1219,555 @@ impl<'a, T: RluBounds> Drop for RluSession<'a, T> {
   }
 }
 
-impl<T: RluBounds> RluThread<T> {
-  fn new() -> RluThread<T> {
-    let mut thread = RluThread {
-      logs: unsafe { mem::uninitialized() },
+impl<T> RluThread<T> {
+  fn new(thread_id: usize, config: &RluConfig) -> RluThread<T> {
+    RluThread {
+      logs: [
+        WriteLog::new(config.log_capacity),
+        WriteLog::new(config.log_capacity),
+      ],
+      log_limit: config.log_limit,
+      retry_policy: config.retry_policy,
       current_log: 0,
       is_writer: false,
       write_clock: usize::MAX,
       local_clock: AtomicUsize::new(0),
       run_counter: AtomicUsize::new(0),
-      thread_id: 0,
+      waiters: Mutex::new(Vec::new()),
+      has_waiters: AtomicBool::new(false),
+      thread_id,
       global: ptr::null(),
-      num_free: 0,
-      free_list: unsafe { mem::uninitialized() },
-    };
+      #[cfg(feature = "stats")]
+      stats: RluThreadStats::default(),
+      free_list: Vec::with_capacity(config.free_capacity),
+      deferred: Vec::new(),
+      allocs: Vec::new(),
+      frees: Vec::new(),
+      session_entries: 0,
+      undo: Vec::new(),
+      sync_requested: AtomicBool::new(false),
+      serializable: false,
+      read_set: RefCell::new(Vec::new()),
+    }
+  }
+
+  // Caps the number of objects a single session may lock. Once the cap is
+  // reached `write_lock` fails rather than growing the log further.
+  pub fn set_log_limit(&mut self, limit: Option<usize>) {
+    self.log_limit = limit;
+  }
+
+  pub fn thread_id(&self) -> usize {
+    self.thread_id
+  }
+
+  pub fn set_retry_policy(&mut self, policy: RluRetryPolicy) {
+    self.retry_policy = policy;
+  }
 
-    for i in 0..2 {
-      thread.logs[i].num_entries = 0;
+  pub fn transaction<R, F>(&mut self, f: F) -> Result<R, TransactionError>
+  where
+    T: RluBounds,
+    F: FnMut(&mut RluSession<'_, T>) -> Result<R, TransactionError>,
+  {
+    self.run_transaction(false, f)
+  }
+
+  // Like `transaction`, but each attempt runs in a serializable session and
+  // one that fails validation is retried as well.
+  pub fn serializable_transaction<R, F>(
+    &mut self,
+    f: F,
+  ) -> Result<R, TransactionError>
+  where
+    T: RluBounds,
+    F: FnMut(&mut RluSession<'_, T>) -> Result<R, TransactionError>,
+  {
+    self.run_transaction(true, f)
+  }
+
+  fn run_transaction<R, F>(
+    &mut self,
+    serializable: bool,
+    mut f: F,
+  ) -> Result<R, TransactionError>
+  where
+    T: RluBounds,
+    F: FnMut(&mut RluSession<'_, T>) -> Result<R, TransactionError>,
+  {
+    let policy = self.retry_policy;
+    let mut attempt = 0;
+    let mut last_err;
+    loop {
+      let mut session = self.begin(serializable);
+      match f(&mut session) {
+        Ok(result) => {
+          if session.commit().is_ok() {
+            return Ok(result);
+          }
+          last_err = TransactionError::Retry;
+        }
+        Err(err) => {
+          session.abort();
+          if !err.is_retryable() {
+            return Err(err);
+          }
+          last_err = err;
+        }
+      }
+
+      attempt += 1;
+      if policy.max_attempts.is_some_and(|max| attempt >= max) {
+        return Err(last_err);
+      }
+
+      policy.backoff.wait(attempt);
+    }
+  }
+
+  pub fn session<'a>(&'a mut self) -> RluSession<'a, T>
+  where
+    T: RluBounds,
+  {
+    self.begin(false)
+  }
+
+  // A writing session that only commits if no object it read through
+  // `read_lock` was locked or rewritten by another thread in the meantime,
+  // which rules out write skew between concurrent writers. Use
+  // `RluSession::commit` to find out whether it did.
+  pub fn serializable_session<'a>(&'a mut self) -> RluSession<'a, T>
+  where
+    T: RluBounds,
+  {
+    self.begin(true)
+  }
+
+  fn begin<'a>(&'a mut self, serializable: bool) -> RluSession<'a, T>
+  where
+    T: RluBounds,
+  {
+    self.start(serializable);
+    RluSession {
+      t: self,
+      abort: false,
+      panicking: thread::panicking(),
     }
+  }
 
-    thread
+  fn start(&mut self, serializable: bool) {
+    self.enter();
+    self.is_writer = false;
+    self.session_entries = self.logs[self.current_log].num_entries;
+    self.serializable = serializable;
+    self.read_set.get_mut().clear();
+  }
+
+  fn session_is_empty(&self) -> bool {
+    self.logs[self.current_log].num_entries == self.session_entries
+      && self.allocs.is_empty()
+      && self.frees.is_empty()
+      && self.read_set.borrow().is_empty()
   }
 
-  pub fn session<'a>(&'a mut self) -> RluSession<'a, T> {
-    log!(self, "lock");
+  pub fn read_session<'a>(&'a mut self) -> RluReadSession<'a, T>
+  where
+    T: RluBounds,
+  {
+    self.enter();
+    RluReadSession { t: self }
+  }
+
+  fn enter(&mut self) {
     let global = unsafe { &*self.global };
+    if self.sync_requested.load(Ordering::SeqCst) {
+      self.flush();
+    }
+
     let cntr = self.run_counter.fetch_add(1, Ordering::SeqCst);
     if cfg!(debug_assertions) {
       assert!(cntr % 2 == 0);
     }
+    stat!(self, sessions);
 
     self
       .local_clock
       .store(global.global_clock.load(Ordering::SeqCst), Ordering::SeqCst);
-    log!(
-      self,
-      format!(
-        "lock with local clock {}",
-        self.local_clock.load(Ordering::SeqCst)
-      )
+    event!(
+      global,
+      RluEvent::SessionBegin {
+        thread_id: self.thread_id,
+        local_clock: self.local_clock.load(Ordering::SeqCst),
+      }
     );
-    self.is_writer = false;
-    RluSession {
-      t: self,
-      abort: false,
+  }
+
+  fn exit(&mut self, aborted: bool) {
+    let cntr = self.run_counter.fetch_add(1, Ordering::SeqCst);
+    if cfg!(debug_assertions) {
+      assert!(cntr % 2 == 1);
+    }
+    self.wake_waiters();
+    event!(
+      unsafe { &*self.global },
+      RluEvent::SessionEnd {
+        thread_id: self.thread_id,
+        aborted,
+      }
+    );
+  }
+
+  fn dereference(&self, obj: RluObject<T>) -> *const T {
+    let global = unsafe { &*self.global };
+    let orig = obj.deref();
+    let local_clock = self.local_clock.load(Ordering::SeqCst);
+    if let Some(copy) = unsafe { orig.copy.load(Ordering::SeqCst).as_ref() } {
+      if self.thread_id == copy.thread_id {
+        return copy.data.as_ptr();
+      }
+
+      // In multi-version mode other writers' copies are never read, their
+      // commits show up in the version chain instead
+      if global.config.max_versions.is_none() {
+        let thread = unsafe { &*global.get_thread(copy.thread_id) };
+        if thread.write_clock <= local_clock {
+          return copy.data.as_ptr();
+        }
+      }
     }
+
+    orig.version_at(local_clock)
   }
 
   pub fn free(&mut self, obj: RluObject<T>) {
-    let free_id = self.num_free;
-    self.num_free += 1;
-    self.free_list[free_id] = obj;
+    self.free_list.push(obj);
+  }
+
+  pub fn num_pending_free(&self) -> usize {
+    self.free_list.len()
+  }
+
+  // Runs `f` once every session that is currently running has ended. The
+  // callback is queued and executed after this thread's next grace period,
+  // i.e. its next commit or `wait_for_readers`, or when the handle is dropped.
+  pub fn call_after_grace_period<F>(&mut self, f: F)
+  where
+    F: FnOnce() + Send + 'static,
+  {
+    self.deferred.push(Box::new(f));
+  }
+
+  pub fn wait_for_readers(&mut self) {
+    // Deferred frees may have been unlinked by writes that are still only in
+    // our log, so they have to be written back before anything is reclaimed
+    if self.logs[self.current_log].num_entries > 0 {
+      self.flush();
+    }
+
+    let global = unsafe { &*self.global };
+    let waits = global.wait_for_readers(Some(self.thread_id), usize::MAX);
+    stat!(self, synchronize_waits, waits);
+    self.process_free();
+  }
+
+  #[cfg(feature = "stats")]
+  pub fn stats(&self) -> RluStats {
+    self.stats.load()
   }
 
   fn process_free(&mut self) {
-    for i in 0..self.num_free {
-      unsafe { Box::from_raw(self.free_list[i].0) };
+    let global = unsafe { &*self.global };
+    stat!(self, frees, self.free_list.len());
+    for obj in self.free_list.drain(..) {
+      global.reclaim(self.thread_id, obj);
     }
 
-    self.num_free = 0;
+    for f in self.deferred.drain(..) {
+      f();
+    }
   }
 
   fn commit_write_log(&mut self) {
     let global = unsafe { &*self.global };
+    if let Some(max_versions) = global.config.max_versions {
+      self.commit_versions(max_versions);
+      return;
+    }
+
     self.write_clock = global.global_clock.fetch_add(1, Ordering::SeqCst) + 1;
-    log!(self, format!("global clock: {}", self.write_clock));
+    event!(
+      global,
+      RluEvent::Commit {
+        thread_id: self.thread_id,
+        write_clock: self.write_clock,
+      }
+    );
+    stat!(self, commits);
     self.synchronize();
     self.writeback_logs();
+    self.retire_frees();
     self.unlock_write_log();
+    self.logs[self.current_log].num_entries = 0;
     self.write_clock = usize::MAX;
     self.swap_logs();
     self.process_free();
   }
 
-  fn unlock(&mut self) {
-    log!(self, "unlock");
-    let cntr = self.run_counter.fetch_add(1, Ordering::SeqCst);
-    if cfg!(debug_assertions) {
-      assert!(cntr % 2 == 1);
+  // Multi-version commit: every copy becomes the newest version of its object
+  // instead of being written back, and sessions that started earlier keep
+  // resolving older versions. The writer only waits for readers when a chain
+  // grows past `max_versions` or it has objects to retire.
+  fn commit_versions(&mut self, max_versions: usize) {
+    let global = unsafe { &*self.global };
+    let active_log = &mut self.logs[self.current_log];
+    for i in 0..active_log.num_entries {
+      let copy = &active_log.entries[i];
+      let orig = copy.original.deref();
+      let version = Box::new(ObjVersion {
+        commit_clock: AtomicUsize::new(RLU_PENDING_CLOCK),
+        data: unsafe { copy.data.assume_init_read() },
+        older: AtomicPtr::new(orig.versions.load(Ordering::SeqCst)),
+      });
+      orig
+        .versions
+        .store(Box::into_raw(version), Ordering::SeqCst);
+    }
+
+    let write_clock = global.global_clock.fetch_add(1, Ordering::SeqCst) + 1;
+    event!(
+      global,
+      RluEvent::Commit {
+        thread_id: self.thread_id,
+        write_clock,
+      }
+    );
+    stat!(self, commits);
+
+    for i in 0..active_log.num_entries {
+      let orig = active_log.entries[i].original.deref();
+      let newest = unsafe { &*orig.versions.load(Ordering::SeqCst) };
+      newest.commit_clock.store(write_clock, Ordering::SeqCst);
     }
 
+    if self.trim_versions() > max_versions.max(1) {
+      let waits = global.wait_for_readers(Some(self.thread_id), usize::MAX);
+      stat!(self, synchronize_waits, waits);
+      self.trim_versions();
+    }
+
+    // The payloads now live in the versions, so the log owns none of them
+    self.retire_frees();
+    self.unlock_write_log();
+    self.logs[self.current_log].num_entries = 0;
+
+    if !self.free_list.is_empty() || !self.deferred.is_empty() {
+      self.wait_for_readers();
+    }
+  }
+
+  // Lets `write_lock_wait` tell that an object it waited for is gone. Has to
+  // happen before the objects are unlocked.
+  fn retire_frees(&self) {
+    for obj in &self.free_list {
+      obj.deref().retired.store(true, Ordering::SeqCst);
+    }
+  }
+
+  // Trims the chain of every object in the log, returning the longest one.
+  fn trim_versions(&mut self) -> usize {
+    let global = unsafe { &*self.global };
+    let min_clock = global.min_reader_clock();
+    let active_log = &self.logs[self.current_log];
+    (0..active_log.num_entries)
+      .map(|i| {
+        active_log.entries[i]
+          .original
+          .deref()
+          .trim_versions(min_clock)
+      })
+      .max()
+      .unwrap_or(0)
+  }
+
+  // Returns false if validation made the session abort instead.
+  fn unlock(&mut self) -> bool {
+    let global = unsafe { &*self.global };
+    if !self.validate() {
+      self.abort();
+      return false;
+    }
+
+    self.exit(false);
+    self.undo.clear();
+
     if self.is_writer {
+      self.free_list.append(&mut self.frees);
+      if !global.config.defer_writeback {
+        self.commit_write_log();
+      }
+    }
+    self.allocs.clear();
+
+    if global.config.defer_writeback {
+      let active_log = &self.logs[self.current_log];
+      let capacity = self.log_limit.unwrap_or(global.config.log_capacity);
+      if active_log.num_entries >= capacity
+        || self.sync_requested.load(Ordering::SeqCst)
+      {
+        self.flush();
+      }
+    }
+
+    true
+  }
+
+  // Checks the read set of a serializable writer. Our own locks keep the
+  // objects we wrote stable, so if nothing we read has been rewritten after
+  // our snapshot or is locked by someone else now, committing at this point
+  // is equivalent to running the whole session here.
+  fn validate(&self) -> bool {
+    if !self.serializable || !self.is_writer {
+      return true;
+    }
+
+    let local_clock = self.local_clock.load(Ordering::SeqCst);
+    self.read_set.borrow().iter().all(|obj| {
+      let orig = obj.deref();
+      match unsafe { orig.copy.load(Ordering::SeqCst).as_ref() } {
+        Some(copy) if copy.thread_id != self.thread_id => false,
+        _ => orig.newest_clock() <= local_clock,
+      }
+    })
+  }
+
+  // Writes back everything a deferred writer has logged so far. Happens on
+  // its own when the log fills up, when another writer asks for one of our
+  // objects (at our next session boundary) and when the handle is dropped,
+  // so an idle thread keeps its objects locked until one of those.
+  pub fn flush(&mut self) {
+    self.sync_requested.store(false, Ordering::SeqCst);
+    if self.logs[self.current_log].num_entries > 0 || !self.free_list.is_empty()
+    {
       self.commit_write_log();
     }
   }
 
+  // Runs after `synchronize`, so nobody can still be reading the old data of
+  // the originals. Each copy is moved into place bitwise, which for types
+  // without drop glue is just a memcpy. Readers that already hold a copy keep
+  // reading it until the next grace period, but the log no longer owns its
+  // payloads afterwards and will not drop them.
   fn writeback_logs(&mut self) {
-    log!(self, "writeback_logs");
+    let write_clock = self.write_clock;
     let active_log = &mut self.logs[self.current_log];
     for i in 0..active_log.num_entries {
       let copy = &mut active_log.entries[i];
-      log!(self, format!("copy {:?} ({:p})", copy.data, &copy.data));
       let orig = copy.original.deref_mut();
-      orig.data = copy.data.clone();
+      unsafe {
+        ptr::drop_in_place(&mut orig.data);
+        ptr::copy_nonoverlapping(copy.data.as_ptr(), &mut orig.data, 1);
+      }
+      // Stamped before the copy is unlocked, see `validate`
+      orig.write_clock.store(write_clock, Ordering::SeqCst);
     }
   }
 
   fn unlock_write_log(&mut self) {
-    log!(self, "unlock_write_log");
     let active_log = &mut self.logs[self.current_log];
     for i in 0..active_log.num_entries {
       let orig = active_log.entries[i].original.deref_mut();
       orig.copy.store(ptr::null_mut(), Ordering::SeqCst);
     }
-    active_log.num_entries = 0;
+    // For writers in `write_lock_wait`
+    self.wake_waiters();
   }
 
+  // Readers that saw a copy of the log we just committed may still be using
+  // it, so its slots are left alone until the next commit. That commit's
+  // grace period covers them, so the other log is safe to reuse now.
   fn swap_logs(&mut self) {
-    log!(self, "swap_logs");
     self.current_log = (self.current_log + 1) % 2;
-    let active_log = &mut self.logs[self.current_log];
-    active_log.num_entries = 0;
   }
 
   fn synchronize(&mut self) {
-    log!(self, "synchronize");
+    let global = unsafe { &*self.global };
+    let waits = global.wait_for_readers(Some(self.thread_id), self.write_clock);
+    stat!(self, synchronize_waits, waits);
+  }
+
+  fn abort(&mut self) {
+    self.exit(true);
+    stat!(self, aborts);
+
+    if self.is_writer {
+      self.rollback();
+    }
 
     let global = unsafe { &*self.global };
-    let num_threads = global.num_threads.load(Ordering::SeqCst);
-    let run_counts: Vec<usize> = (0..num_threads)
-      .map(|i| global.threads[i].run_counter.load(Ordering::SeqCst))
-      .collect();
+    for obj in self.allocs.drain(..) {
+      global.reclaim(self.thread_id, obj);
+    }
+    self.frees.clear();
 
-    for i in 0..num_threads {
-      if i == self.thread_id {
-        continue;
-      }
+    if self.sync_requested.load(Ordering::SeqCst) {
+      self.flush();
+    }
+  }
 
-      let thread = &global.threads[i];
-      loop {
-        log!(self, format!("wait on thread {}: rc {}, counter {}, write clock {}, local clock {}", i, run_counts[i], thread.run_counter.load(Ordering::SeqCst), self.write_clock, thread.local_clock.load(Ordering::SeqCst)));
+  // Undoes the running session's writes. Nobody reads an uncommitted copy
+  // but its owner, so the session's own entries can go at once, while
+  // entries deferred by earlier sessions get their previous data back.
+  fn rollback(&mut self) {
+    for (copy, data) in self.undo.drain(..) {
+      unsafe { *(*copy).data.assume_init_mut() = data };
+    }
 
-        if run_counts[i] % 2 == 0
-          || thread.run_counter.load(Ordering::SeqCst) != run_counts[i]
-          || self.write_clock <= thread.local_clock.load(Ordering::SeqCst)
-        {
-          break;
-        }
+    self.unlock_entries_from(self.session_entries);
+  }
 
-        thread::yield_now();
-      }
+  // Unlocks and drops the log entries from `start` onwards.
+  fn unlock_entries_from(&mut self, start: usize) {
+    let active_log = &mut self.logs[self.current_log];
+    while active_log.num_entries > start {
+      let copy = &active_log.entries[active_log.num_entries - 1];
+      copy
+        .original
+        .deref()
+        .copy
+        .store(ptr::null_mut(), Ordering::SeqCst);
+      active_log.pop_entry();
     }
+    self.wake_waiters();
   }
 
-  fn abort(&mut self) {
-    log!(self, "abort");
-    let cntr = self.run_counter.fetch_add(1, Ordering::SeqCst);
-    if cfg!(debug_assertions) {
-      assert!(cntr % 2 == 1);
+  // Called by a writer waiting on this thread. The waiter registers itself
+  // before rechecking `done`, and this thread checks `has_waiters` after
+  // bumping its run counter or releasing its locks, so one side always sees
+  // the other. Wakeups can be spurious, so callers loop on `done`.
+  fn park_until(&self, done: &dyn Fn() -> bool, deadline: Option<Instant>) {
+    {
+      let mut waiters = self.waiters.lock().unwrap();
+      waiters.push(thread::current());
+      self.has_waiters.store(true, Ordering::SeqCst);
     }
 
-    if self.is_writer {
-      self.unlock_write_log();
+    if !done() {
+      match deadline {
+        None => thread::park(),
+        Some(deadline) => thread::park_timeout(
+          deadline.saturating_duration_since(Instant::now()),
+        ),
+      }
+    }
+  }
+
+  fn wake_waiters(&self) {
+    if self.has_waiters.load(Ordering::SeqCst)
+      && self.has_waiters.swap(false, Ordering::SeqCst)
+    {
+      for waiter in self.waiters.lock().unwrap().drain(..) {
+        waiter.unpark();
+      }
     }
   }
 }
@@ -1,16 +1,20 @@
 #![allow(unused_mut, unused_variables, unused_imports)]
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::{thread, time};
 
-use rlu::Rlu;
+use rlu::{
+  Rlu, RluBackoff, RluConfig, RluRetry, RluRetryPolicy, RluWaitStrategy,
+  TransactionError, WriteLockError,
+};
 
 #[test]
 fn basic_single() {
   let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
   let mut obj = rlu.alloc(3);
-  let thread = rlu.thread();
+  let mut thread = rlu.thread();
 
   {
     let mut lock = thread.session();
@@ -18,28 +22,22 @@ fn basic_single() {
     // Object should have original value after first deref
     {
       let n = lock.read_lock(obj);
-      unsafe {
-        assert_eq!(*n, 3);
-      }
+      assert_eq!(*n, 3);
     }
 
     // Object should still have same value, but now it's safe to write
     // We should have a copy at this point
     {
-      let n = lock.write_lock(obj).unwrap();
-      unsafe {
-        assert_eq!(*n, 3);
-        *n += 1;
-      }
+      let mut n = lock.write_lock(obj).unwrap();
+      assert_eq!(*n, 3);
+      *n += 1;
     }
 
     // Subsequent derefs in same thread should refer to the copy, observing
     // the new value
     {
       let n = lock.read_lock(obj);
-      unsafe {
-        assert_eq!(*n, 4);
-      }
+      assert_eq!(*n, 4);
     }
   }
 
@@ -50,9 +48,7 @@ fn basic_single() {
     // Read should observed flushed change
     {
       let n = lock.read_lock(obj);
-      unsafe {
-        assert_eq!(*n, 4);
-      }
+      assert_eq!(*n, 4);
     }
   }
 }
@@ -62,44 +58,36 @@ fn basic_overlapping_reader_writer() {
   let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
   let mut obj = rlu.alloc(3);
 
-  let thread0 = rlu.thread();
-  let thread1 = rlu.thread();
+  let mut thread0 = rlu.thread();
+  let mut thread1 = rlu.thread();
 
   {
     let mut lock1 = thread1.session();
     let mut lock0 = thread0.session();
 
     {
-      let n1: *const u64 = lock0.read_lock(obj);
-      unsafe {
-        assert_eq!(*n1, 3);
-      }
+      let n1 = lock0.read_lock(obj);
+      assert_eq!(*n1, 3);
     }
 
     // Thread 1 should be working on a copy
     {
-      let n2: *mut u64 = lock1.write_lock(obj).unwrap();
-      unsafe {
-        assert_eq!(*n2, 3);
-        *n2 += 1;
-      }
+      let mut n2 = lock1.write_lock(obj).unwrap();
+      assert_eq!(*n2, 3);
+      *n2 += 1;
     }
 
     // Thread 0 should be working on the original
     {
-      let n1: *const u64 = lock0.read_lock(obj);
-      unsafe {
-        assert_eq!(*n1, 3);
-      }
+      let n1 = lock0.read_lock(obj);
+      assert_eq!(*n1, 3);
     }
 
     // Thread 0 exits, allowing thread 1 to flush writes
   }
 
   let mut lock = thread0.session();
-  unsafe {
-    assert_eq!(*lock.read_lock(obj), 4);
-  }
+  assert_eq!(*lock.read_lock(obj), 4);
 }
 
 #[test]
@@ -110,14 +98,14 @@ fn basic_thread() {
   let reader = |id: u64| {
     let rlu = rlu.clone();
     thread::spawn(move || {
-      let thr = rlu.thread();
+      let mut thr = rlu.thread();
 
       for _ in 0..100 {
         let mut lock = thr.session();
         let n = lock.read_lock(obj);
-        let x = unsafe { *n };
+        let x = *n;
         thread::sleep(time::Duration::from_millis(10));
-        assert_eq!(unsafe { *n }, x);
+        assert_eq!(*n, x);
       }
 
       println!("Reader {} exit", id);
@@ -127,7 +115,7 @@ fn basic_thread() {
   let writer = |id: u64| {
     let rlu = rlu.clone();
     thread::spawn(move || {
-      let thr = rlu.thread();
+      let mut thr = rlu.thread();
 
       for i in 0..1000 {
         // if i % 100 == 0 {
@@ -135,13 +123,14 @@ fn basic_thread() {
         // }
         loop {
           let mut lock = thr.session();
-          if let Some(n) = lock.write_lock(obj) {
-            unsafe {
+          match lock.write_lock(obj) {
+            Ok(mut n) => {
               *n += 1;
+              break;
+            }
+            Err(_) => {
+              lock.abort();
             }
-            break;
-          } else {
-            lock.abort();
           }
         }
       }
@@ -164,7 +153,906 @@ fn basic_thread() {
     t.join().expect("Writer panicked");
   }
 
-  let thr = rlu.thread();
+  let mut thr = rlu.thread();
+  let mut lock = thr.session();
+  assert_eq!(*lock.read_lock(obj), 1000 * num_writers);
+}
+
+#[test]
+fn basic_thread_reuse() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let mut obj = rlu.alloc(0);
+
+  // Registering far more threads than there are slots should succeed as long
+  // as earlier handles have been dropped
+  for _ in 0..100 {
+    let mut thr = rlu.thread();
+    let mut lock = thr.session();
+    *lock.write_lock(obj).unwrap() += 1;
+  }
+
+  let mut thr = rlu.thread();
+  let mut lock = thr.session();
+  assert_eq!(*lock.read_lock(obj), 100);
+}
+
+#[test]
+fn basic_large_log() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let objs: Vec<_> = (0..500).map(|i| rlu.alloc(i)).collect();
+  let mut thr = rlu.thread();
+
+  {
+    let mut lock = thr.session();
+    for obj in &objs {
+      *lock.write_lock(*obj).unwrap() += 1;
+    }
+  }
+
+  {
+    let mut lock = thr.session();
+    for (i, obj) in objs.iter().enumerate() {
+      assert_eq!(*lock.read_lock(*obj), i as u64 + 1);
+    }
+  }
+
+  // With a hard limit configured, locking past it fails cleanly
+  thr.set_log_limit(Some(2));
+  let mut lock = thr.session();
+  assert!(lock.write_lock(objs[0]).is_ok());
+  assert!(lock.write_lock(objs[1]).is_ok());
+  assert_eq!(
+    lock.write_lock(objs[2]).err(),
+    Some(WriteLockError::LogFull)
+  );
+  lock.abort();
+}
+
+#[test]
+fn basic_bulk_free() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let mut obj = rlu.alloc(0);
+  let mut thr = rlu.thread();
+
+  {
+    let mut lock = thr.session();
+    *lock.write_lock(obj).unwrap() += 1;
+  }
+
+  for i in 0..500 {
+    let garbage = rlu.alloc(i);
+    thr.free(garbage);
+  }
+  assert_eq!(thr.num_pending_free(), 500);
+
+  // Retired objects are reclaimed once the next writer commits
+  {
+    let mut lock = thr.session();
+    *lock.write_lock(obj).unwrap() += 1;
+  }
+  assert_eq!(thr.num_pending_free(), 0);
+  assert_eq!(rlu.num_objects(), 1);
+}
+
+#[test]
+fn basic_config() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::with_config(RluConfig {
+    max_threads: 64,
+    log_capacity: 1,
+    ..Default::default()
+  }));
+  let mut obj = rlu.alloc(0);
+
+  let threads: Vec<_> = (0..48)
+    .map(|_| {
+      let rlu = rlu.clone();
+      thread::spawn(move || {
+        let mut thr = rlu.thread();
+        loop {
+          let mut lock = thr.session();
+          match lock.write_lock(obj) {
+            Ok(mut n) => {
+              *n += 1;
+              break;
+            }
+            Err(_) => {
+              lock.abort();
+            }
+          }
+        }
+      })
+    })
+    .collect();
+
+  for t in threads {
+    t.join().unwrap();
+  }
+
+  let mut thr = rlu.thread();
+  let mut lock = thr.session();
+  assert_eq!(*lock.read_lock(obj), 48);
+}
+
+#[test]
+fn basic_large_payload() {
+  // Domains only pay for thread state once threads register, so many domains
+  // over a large payload type are cheap to create
+  let domains: Vec<Arc<Rlu<Vec<[u64; 1024]>>>> = (0..64)
+    .map(|_| {
+      Arc::new(Rlu::with_config(RluConfig {
+        max_threads: 256,
+        ..Default::default()
+      }))
+    })
+    .collect();
+
+  let rlu = &domains[0];
+  let mut obj = rlu.alloc(vec![[0; 1024]]);
+  let mut thr = rlu.thread();
+
+  {
+    let mut lock = thr.session();
+    lock.write_lock(obj).unwrap()[0][1023] = 1;
+  }
+
+  let mut lock = thr.session();
+  assert_eq!(lock.read_lock(obj)[0][1023], 1);
+}
+
+#[test]
+fn basic_transaction() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let mut obj = rlu.alloc(0);
+  let mut thr0 = rlu.thread();
+  let mut thr1 = rlu.thread();
+
+  thr1.set_retry_policy(RluRetryPolicy {
+    backoff: RluBackoff::Spin,
+    max_attempts: Some(3),
+  });
+
+  let thread_id0 = thr0.thread_id();
+
+  {
+    let mut lock0 = thr0.session();
+    *lock0.write_lock(obj).unwrap() += 1;
+
+    {
+      let mut lock1 = thr1.session();
+      assert_eq!(
+        lock1.write_lock(obj).err(),
+        Some(WriteLockError::HeldBy {
+          thread_id: thread_id0
+        })
+      );
+      lock1.abort();
+    }
+
+    // Thread 0 holds the lock, so thread 1 should give up after 3 attempts
+    let mut attempts = 0;
+    let result = thr1.transaction(|lock1| {
+      attempts += 1;
+      *lock1.write_lock(obj)? += 1;
+      Ok(())
+    });
+    assert_eq!(
+      result,
+      Err(TransactionError::WriteLock(WriteLockError::HeldBy {
+        thread_id: thread_id0
+      }))
+    );
+    assert_eq!(attempts, 3);
+  }
+
+  let n = thr1
+    .transaction(|lock1| {
+      let mut n = lock1.write_lock(obj)?;
+      *n += 1;
+      Ok(*n)
+    })
+    .unwrap();
+  assert_eq!(n, 2);
+
+  // A full log won't get any emptier, so the error is returned right away
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::with_config(RluConfig {
+    log_limit: Some(1),
+    ..RluConfig::default()
+  }));
+  let obj1 = rlu.alloc(0);
+  let obj2 = rlu.alloc(0);
+  let mut thr = rlu.thread();
+  let mut attempts = 0;
+  let result = thr.transaction(|lock| {
+    attempts += 1;
+    *lock.write_lock(obj1)? += 1;
+    *lock.write_lock(obj2)? += 1;
+    Ok(())
+  });
+  assert_eq!(
+    result,
+    Err(TransactionError::WriteLock(WriteLockError::LogFull))
+  );
+  assert_eq!(attempts, 1);
+  assert_eq!(*thr.read_session().read_lock(obj1), 0);
+}
+
+#[test]
+fn basic_panic_abort() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let mut obj = rlu.alloc(3);
+
+  let writer = {
+    let rlu = rlu.clone();
+    thread::spawn(move || {
+      let mut thr = rlu.thread();
+      let mut lock = thr.session();
+      *lock.write_lock(obj).unwrap() += 1;
+      panic!("Writer panicked mid-session");
+    })
+  };
+  assert!(writer.join().is_err());
+
+  // The panicking writer should have rolled back and released its lock
+  let mut thr = rlu.thread();
+  {
+    let mut lock = thr.session();
+    assert_eq!(*lock.read_lock(obj), 3);
+    assert!(lock.write_lock(obj).is_ok());
+    lock.abort();
+  }
+
+  // A session that begins and ends inside a destructor run by the unwind
+  // is unaffected by it
+  struct Cleanup(Arc<Rlu<u64>>, rlu::RluObject<u64>);
+  impl Drop for Cleanup {
+    fn drop(&mut self) {
+      let mut thr = self.0.thread();
+      let mut lock = thr.session();
+      *lock.write_lock(self.1).unwrap() += 1;
+    }
+  }
+
+  let writer = {
+    let rlu = rlu.clone();
+    thread::spawn(move || {
+      let _cleanup = Cleanup(rlu, obj);
+      panic!("Writer panicked outside a session");
+    })
+  };
+  assert!(writer.join().is_err());
+
+  let lock = thr.read_session();
+  assert_eq!(*lock.read_lock(obj), 4);
+}
+
+#[test]
+fn basic_grace_period() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let mut obj = rlu.alloc(0);
+  let reader_done = Arc::new(AtomicBool::new(false));
+  let (tx, rx) = mpsc::channel();
+
+  let reader = {
+    let rlu = rlu.clone();
+    let reader_done = reader_done.clone();
+    thread::spawn(move || {
+      let mut thr = rlu.thread();
+      let lock = thr.session();
+      tx.send(()).unwrap();
+      thread::sleep(time::Duration::from_millis(100));
+      reader_done.store(true, Ordering::SeqCst);
+    })
+  };
+
+  rx.recv().unwrap();
+  rlu.synchronize();
+  assert!(reader_done.load(Ordering::SeqCst));
+  reader.join().unwrap();
+
+  // Deferred callbacks only run once the thread observes a grace period
+  let called = Arc::new(AtomicBool::new(false));
+  let mut thr = rlu.thread();
+  {
+    let called = called.clone();
+    thr.call_after_grace_period(move || called.store(true, Ordering::SeqCst));
+  }
+  assert!(!called.load(Ordering::SeqCst));
+  thr.wait_for_readers();
+  assert!(called.load(Ordering::SeqCst));
+
+  // Dropping the handle doesn't leave callbacks behind
+  called.store(false, Ordering::SeqCst);
+  {
+    let called = called.clone();
+    thr.call_after_grace_period(move || called.store(true, Ordering::SeqCst));
+  }
+  drop(thr);
+  assert!(called.load(Ordering::SeqCst));
+}
+
+#[test]
+fn basic_park() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::with_config(RluConfig {
+    wait_strategy: RluWaitStrategy::Park,
+    ..Default::default()
+  }));
+  let mut obj = rlu.alloc(0);
+
+  let readers: Vec<_> = (0..4)
+    .map(|_| {
+      let rlu = rlu.clone();
+      thread::spawn(move || {
+        let mut thr = rlu.thread();
+        for _ in 0..20 {
+          let lock = thr.session();
+          let n = lock.read_lock(obj);
+          thread::sleep(time::Duration::from_millis(1));
+          assert!(*n <= 200);
+        }
+      })
+    })
+    .collect();
+
+  let writers: Vec<_> = (0..2)
+    .map(|_| {
+      let rlu = rlu.clone();
+      thread::spawn(move || {
+        let mut thr = rlu.thread();
+        for _ in 0..100 {
+          thr
+            .transaction(|lock| {
+              *lock.write_lock(obj)? += 1;
+              Ok(())
+            })
+            .unwrap();
+        }
+      })
+    })
+    .collect();
+
+  for t in readers.into_iter().chain(writers) {
+    t.join().unwrap();
+  }
+
+  let mut thr = rlu.thread();
+  let lock = thr.session();
+  assert_eq!(*lock.read_lock(obj), 200);
+}
+
+#[test]
+fn basic_drop() {
+  let payload = Arc::new(());
+  let rlu: Arc<Rlu<Arc<()>>> = Arc::new(Rlu::new());
+  let objs: Vec<_> = (0..10).map(|_| rlu.alloc(payload.clone())).collect();
+  let mut thr = rlu.thread();
+
+  {
+    let mut lock = thr.session();
+    *lock.write_lock(objs[0]).unwrap() = payload.clone();
+  }
+
+  // Neither retired nor live objects outlive the domain
+  thr.free(objs[1]);
+  assert_eq!(thr.num_pending_free(), 1);
+  assert_eq!(rlu.num_objects(), 10);
+
+  drop(thr);
+  drop(rlu);
+  assert_eq!(Arc::strong_count(&payload), 1);
+}
+
+#[test]
+fn basic_payload_drop() {
+  let payload = Arc::new(());
+  let rlu: Arc<Rlu<Arc<()>>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(payload.clone());
+  let other = rlu.alloc(Arc::new(()));
+  let mut thr0 = rlu.thread();
+  let mut thr1 = rlu.thread();
+  assert_eq!(Arc::strong_count(&payload), 2);
+
+  // Aborted and conflicting copies are dropped straight away
+  {
+    let mut lock0 = thr0.session();
+    lock0.write_lock(obj).unwrap();
+    assert_eq!(Arc::strong_count(&payload), 3);
+
+    let mut lock1 = thr1.session();
+    assert!(lock1.write_lock(obj).is_err());
+    assert_eq!(Arc::strong_count(&payload), 3);
+    lock1.abort();
+
+    lock0.abort();
+  }
+  assert_eq!(Arc::strong_count(&payload), 2);
+
+  {
+    let mut lock = thr0.session();
+    *lock.write_lock(obj).unwrap() = payload.clone();
+  }
+
+  // Committing moves the copy into place rather than cloning it
+  assert_eq!(Arc::strong_count(&payload), 2);
+  {
+    let mut lock = thr0.session();
+    lock.write_lock(other).unwrap();
+  }
+  assert_eq!(Arc::strong_count(&payload), 2);
+
+  drop(thr0);
+  drop(thr1);
+  drop(rlu);
+  assert_eq!(Arc::strong_count(&payload), 1);
+}
+
+#[test]
+fn basic_session_alloc() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let mut thr = rlu.thread();
+
+  // Objects allocated by an aborted session are reclaimed with it
+  {
+    let mut lock = thr.session();
+    let obj = lock.alloc(1);
+    *lock.write_lock(obj).unwrap() += 1;
+    assert_eq!(rlu.num_objects(), 1);
+    lock.abort();
+  }
+  assert_eq!(rlu.num_objects(), 0);
+
+  let obj = {
+    let mut lock = thr.session();
+    lock.alloc(1)
+  };
+  assert_eq!(rlu.num_objects(), 1);
+
+  let lock = thr.session();
+  assert_eq!(*lock.read_lock(obj), 1);
+}
+
+#[test]
+fn basic_session_free() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(1);
+  let mut thr = rlu.thread();
+
+  // Retiring an object is undone along with the rest of an aborted session
+  {
+    let mut lock = thr.session();
+    lock.free(obj);
+    lock.abort();
+  }
+  assert_eq!(thr.num_pending_free(), 0);
+  assert_eq!(rlu.num_objects(), 1);
+
+  {
+    let lock = thr.session();
+    assert_eq!(*lock.read_lock(obj), 1);
+  }
+
+  {
+    let mut lock = thr.session();
+    lock.free(obj);
+  }
+  assert_eq!(thr.num_pending_free(), 0);
+  assert_eq!(rlu.num_objects(), 0);
+
+  // Objects can be freed by a different thread than the one that made them
+  let obj = {
+    let mut lock = thr.session();
+    lock.alloc(2)
+  };
+  let mut thr1 = rlu.thread();
+  {
+    let mut lock = thr1.session();
+    lock.free(obj);
+  }
+  assert_eq!(rlu.num_objects(), 0);
+}
+
+#[test]
+#[should_panic(expected = "not live")]
+fn basic_session_double_free() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(1);
+  let mut thr = rlu.thread();
+
   let mut lock = thr.session();
-  assert_eq!(unsafe { *lock.read_lock(obj) }, 1000 * num_writers);
+  lock.free(obj);
+  lock.free(obj);
+  lock.commit().unwrap();
+}
+
+#[test]
+fn basic_read_session() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(3);
+
+  let mut thread0 = rlu.thread();
+  let mut thread1 = rlu.thread();
+
+  {
+    let mut lock1 = thread1.session();
+    let lock0 = thread0.read_session();
+
+    *lock1.write_lock(obj).unwrap() += 1;
+    assert_eq!(*lock0.read_lock(obj), 3);
+
+    // The reader leaves first, so the writer's commit does not wait on it
+  }
+
+  let lock = thread0.read_session();
+  assert_eq!(*lock.read_lock(obj), 4);
+}
+
+#[test]
+fn basic_multi_version() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::with_config(RluConfig {
+    max_versions: Some(4),
+    ..Default::default()
+  }));
+  let obj = rlu.alloc(3);
+  let mut thr0 = rlu.thread();
+  let mut thr1 = rlu.thread();
+
+  {
+    let lock0 = thr0.read_session();
+
+    // Writers commit without waiting for the reader, which keeps seeing the
+    // version that matches its snapshot
+    for _ in 0..2 {
+      let mut lock1 = thr1.session();
+      *lock1.write_lock(obj).unwrap() += 1;
+    }
+    assert_eq!(*lock0.read_lock(obj), 3);
+
+    let lock1 = thr1.read_session();
+    assert_eq!(*lock1.read_lock(obj), 5);
+  }
+
+  // Writing over a version newer than the snapshot must fail
+  {
+    let mut lock0 = thr0.session();
+    {
+      let mut lock1 = thr1.session();
+      *lock1.write_lock(obj).unwrap() += 1;
+    }
+    assert_eq!(lock0.write_lock(obj).err(), Some(WriteLockError::Outdated));
+    lock0.abort();
+  }
+
+  let lock = thr0.read_session();
+  assert_eq!(*lock.read_lock(obj), 6);
+}
+
+#[test]
+fn basic_multi_version_trim() {
+  let payload = Arc::new(());
+  let rlu: Arc<Rlu<Arc<()>>> = Arc::new(Rlu::with_config(RluConfig {
+    max_versions: Some(2),
+    ..Default::default()
+  }));
+  let obj = rlu.alloc(payload.clone());
+  let mut thr = rlu.thread();
+
+  // Without readers, old versions are dropped as soon as they are replaced
+  for _ in 0..100 {
+    let mut lock = thr.session();
+    *lock.write_lock(obj).unwrap() = payload.clone();
+  }
+  assert!(Arc::strong_count(&payload) <= 3);
+
+  drop(thr);
+  drop(rlu);
+  assert_eq!(Arc::strong_count(&payload), 1);
+}
+
+#[test]
+fn basic_deferred() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::with_config(RluConfig {
+    defer_writeback: true,
+    ..Default::default()
+  }));
+  let obj = rlu.alloc(3);
+  let other = rlu.alloc(0);
+  let mut thr0 = rlu.thread();
+  let mut thr1 = rlu.thread();
+  let thread_id0 = thr0.thread_id();
+
+  // Writes stay private to the writer until they are flushed
+  {
+    let mut lock = thr0.session();
+    *lock.write_lock(obj).unwrap() += 1;
+  }
+  assert_eq!(*thr1.read_session().read_lock(obj), 3);
+  assert_eq!(*thr0.read_session().read_lock(obj), 4);
+
+  // A conflicting writer makes the owner flush at its next session
+  {
+    let mut lock = thr1.session();
+    assert_eq!(
+      lock.write_lock(obj).err(),
+      Some(WriteLockError::HeldBy {
+        thread_id: thread_id0
+      })
+    );
+    lock.abort();
+  }
+  drop(thr0.read_session());
+  assert_eq!(*thr1.read_session().read_lock(obj), 4);
+
+  // An abort only undoes the current session
+  {
+    let mut lock = thr0.session();
+    *lock.write_lock(obj).unwrap() += 1;
+  }
+  {
+    let mut lock = thr0.session();
+    *lock.write_lock(obj).unwrap() += 1;
+    *lock.write_lock(other).unwrap() += 1;
+    lock.abort();
+  }
+  assert_eq!(*thr0.read_session().read_lock(obj), 5);
+  assert_eq!(*thr0.read_session().read_lock(other), 0);
+
+  thr0.flush();
+  assert_eq!(*thr1.read_session().read_lock(obj), 5);
+
+  // Dropping the handle flushes whatever is left
+  {
+    let mut lock = thr0.session();
+    *lock.write_lock(obj).unwrap() += 1;
+  }
+  drop(thr0);
+  assert_eq!(*thr1.read_session().read_lock(obj), 6);
+}
+
+#[test]
+fn basic_deferred_free() {
+  #[derive(Clone, Debug)]
+  struct Node(Option<rlu::RluObject<Node>>);
+
+  let rlu: Arc<Rlu<Node>> = Arc::new(Rlu::with_config(RluConfig {
+    defer_writeback: true,
+    ..Default::default()
+  }));
+  let node = rlu.alloc(Node(None));
+  let head = rlu.alloc(Node(Some(node)));
+  let mut thr0 = rlu.thread();
+  let mut thr1 = rlu.thread();
+
+  {
+    let mut lock = thr0.session();
+    lock.write_lock(head).unwrap().0 = None;
+    lock.free(node);
+  }
+
+  // Reclaiming `node` has to wait until the unlink is visible
+  thr0.wait_for_readers();
+  assert!(thr1.read_session().read_lock(head).0.is_none());
+  assert_eq!(rlu.num_objects(), 1);
+}
+
+#[test]
+fn basic_serializable() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let x = rlu.alloc(1);
+  let y = rlu.alloc(1);
+  let mut thr0 = rlu.thread();
+  let mut thr1 = rlu.thread();
+
+  // Each session keeps x + y >= 1 on its own, but together they would break
+  // it. Validation catches the session that read what the other one wrote.
+  {
+    let mut lock0 = thr0.serializable_session();
+    let mut lock1 = thr1.serializable_session();
+
+    if *lock0.read_lock(y) >= 1 {
+      *lock0.write_lock(x).unwrap() = 0;
+    }
+    if *lock1.read_lock(x) >= 1 {
+      *lock1.write_lock(y).unwrap() = 0;
+    }
+
+    assert_eq!(lock1.commit(), Err(RluRetry));
+    assert_eq!(lock0.commit(), Ok(()));
+  }
+
+  let lock = thr0.read_session();
+  assert_eq!(*lock.read_lock(x) + *lock.read_lock(y), 1);
+}
+
+#[test]
+fn basic_serializable_transaction() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let x = rlu.alloc(1);
+  let y = rlu.alloc(1);
+
+  // Writers only take a unit from one object if that leaves at least one in
+  // total, so the sum never drops below one
+  let writers: Vec<_> = (0..4)
+    .map(|i| {
+      let rlu = rlu.clone();
+      thread::spawn(move || {
+        let mut thr = rlu.thread();
+        let (from, to) = if i % 2 == 0 { (x, y) } else { (y, x) };
+        for _ in 0..100 {
+          thr
+            .serializable_transaction(|lock| {
+              let (a, b) = (*lock.read_lock(from), *lock.read_lock(to));
+              if a >= 1 && a + b >= 2 {
+                *lock.write_lock(from)? -= 1;
+              } else {
+                *lock.write_lock(to)? += 1;
+              }
+              Ok(())
+            })
+            .unwrap();
+        }
+      })
+    })
+    .collect();
+
+  for t in writers {
+    t.join().unwrap();
+  }
+
+  let mut thr = rlu.thread();
+  let lock = thr.read_session();
+  assert!(*lock.read_lock(x) + *lock.read_lock(y) >= 1);
+}
+
+#[test]
+fn basic_write_lock_all() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let objs: Vec<_> = (0..3).map(|i| rlu.alloc(i)).collect();
+  let mut thr0 = rlu.thread();
+  let mut thr1 = rlu.thread();
+  let thread_id1 = thr1.thread_id();
+
+  {
+    let mut lock = thr0.session();
+    for (i, mut guard) in
+      lock.write_lock_all(&objs).unwrap().into_iter().enumerate()
+    {
+      assert_eq!(*guard, i as u64);
+      *guard += 10;
+    }
+  }
+
+  {
+    let mut lock0 = thr0.session();
+    let mut lock1 = thr1.session();
+    lock0.write_lock(objs[0]).unwrap();
+    lock1.write_lock(objs[2]).unwrap();
+
+    // Only the locks taken by the failed call are released
+    assert_eq!(
+      lock0.write_lock_all(&[objs[2], objs[1], objs[0]]).err(),
+      Some(WriteLockError::HeldBy {
+        thread_id: thread_id1
+      })
+    );
+    assert!(lock1.write_lock(objs[0]).is_err());
+    assert!(lock1.write_lock(objs[1]).is_ok());
+
+    lock1.abort();
+    lock0.abort();
+  }
+
+  let lock = thr0.read_session();
+  assert_eq!(*lock.read_lock(objs[2]), 12);
+}
+
+#[test]
+#[should_panic(expected = "twice")]
+fn basic_write_lock_all_duplicate() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(0);
+  let mut thr = rlu.thread();
+  let mut lock = thr.session();
+  let _ = lock.write_lock_all(&[obj, obj]);
+}
+
+#[test]
+fn basic_write_lock_wait() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(0);
+  let other = rlu.alloc(0);
+  let mut thr0 = rlu.thread();
+
+  {
+    let mut thr1 = rlu.thread();
+    let thread_id1 = thr1.thread_id();
+    let mut lock1 = thr1.session();
+    let mut lock0 = thr0.session();
+    lock1.write_lock(obj).unwrap();
+
+    // A session that already holds locks isn't restarted behind our back
+    *lock0.write_lock(other).unwrap() = 42;
+    assert_eq!(
+      lock0
+        .write_lock_wait(obj, time::Duration::from_millis(10))
+        .err(),
+      Some(WriteLockError::HeldBy {
+        thread_id: thread_id1
+      })
+    );
+    assert_eq!(*lock0.write_lock(other).unwrap(), 42);
+    lock0.abort();
+
+    // An empty one waits and times out
+    let mut lock0 = thr0.session();
+    assert_eq!(
+      lock0
+        .write_lock_wait(obj, time::Duration::from_millis(10))
+        .err(),
+      Some(WriteLockError::Timeout)
+    );
+
+    lock0.abort();
+    lock1.abort();
+  }
+
+  let (tx, rx) = mpsc::channel();
+  let owner = {
+    let rlu = rlu.clone();
+    thread::spawn(move || {
+      let mut thr = rlu.thread();
+      let mut lock = thr.session();
+      *lock.write_lock(obj).unwrap() += 1;
+      tx.send(()).unwrap();
+      thread::sleep(time::Duration::from_millis(50));
+    })
+  };
+
+  // The owner commits while we wait, and we pick up its value
+  rx.recv().unwrap();
+  {
+    let mut lock = thr0.session();
+    let mut n = lock
+      .write_lock_wait(obj, time::Duration::from_secs(10))
+      .unwrap();
+    assert_eq!(*n, 1);
+    *n += 1;
+  }
+  owner.join().unwrap();
+
+  let lock = thr0.read_session();
+  assert_eq!(*lock.read_lock(obj), 2);
+}
+
+#[test]
+fn basic_write_lock_wait_freed() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(0);
+  let mut thr = rlu.thread();
+
+  let (tx, rx) = mpsc::channel();
+  let owner = {
+    let rlu = rlu.clone();
+    thread::spawn(move || {
+      let mut thr = rlu.thread();
+      let mut lock = thr.session();
+      *lock.write_lock(obj).unwrap() += 1;
+      lock.free(obj);
+      tx.send(()).unwrap();
+      thread::sleep(time::Duration::from_millis(50));
+    })
+  };
+
+  // The owner frees the object while we wait on it, which must neither
+  // reclaim it under us nor hand us a lock on it
+  rx.recv().unwrap();
+  {
+    let mut lock = thr.session();
+    assert_eq!(
+      lock
+        .write_lock_wait(obj, time::Duration::from_secs(10))
+        .err(),
+      Some(WriteLockError::Freed)
+    );
+  }
+  owner.join().unwrap();
+  assert_eq!(rlu.num_objects(), 0);
 }
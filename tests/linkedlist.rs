@@ -1,6 +1,6 @@
 extern crate rand;
 
-use rlu::RluList;
+use rlu::{RluConfig, RluList, TransactionError, WriteLockError};
 use std::thread;
 
 use rand::{random, thread_rng, Rng};
@@ -12,14 +12,14 @@ fn ll_simple() {
   {
     {
       assert!(ll.contains(0).is_none());
-      assert!(ll.delete(0).is_none());
-      assert!(ll.insert(2).is_some());
+      assert_eq!(ll.delete(0), Ok(false));
+      assert_eq!(ll.insert(2), Ok(true));
       println!("Ins 0: {}", ll.to_string());
     }
 
     {
-      assert!(ll.insert(0).is_some());
-      assert!(ll.insert(1).is_some());
+      assert_eq!(ll.insert(0), Ok(true));
+      assert_eq!(ll.insert(1), Ok(true));
       println!("Ins 1: {}", ll.to_string());
     }
 
@@ -33,7 +33,7 @@ fn ll_simple() {
     }
 
     {
-      assert!(ll.delete(1).is_some());
+      assert_eq!(ll.delete(1), Ok(true));
       println!("Del 1: {}", ll.to_string());
     }
 
@@ -42,10 +42,10 @@ fn ll_simple() {
     }
 
     {
-      assert!(ll.delete(0).is_some());
+      assert_eq!(ll.delete(0), Ok(true));
       assert!(ll.contains(0).is_none());
 
-      assert!(ll.delete(2).is_some());
+      assert_eq!(ll.delete(2), Ok(true));
       println!("Del 2: {}", ll.to_string());
     }
   }
@@ -53,12 +53,52 @@ fn ll_simple() {
 
 #[test]
 fn ll_thread() {
-  let mut ll = RluList::new();
+  ll_stress(RluList::new());
+}
+
+#[test]
+fn ll_multi_version() {
+  ll_stress(RluList::with_config(RluConfig {
+    max_versions: Some(4),
+    ..Default::default()
+  }));
+}
+
+#[test]
+fn ll_deferred() {
+  ll_stress(RluList::with_config(RluConfig {
+    defer_writeback: true,
+    ..Default::default()
+  }));
+}
+
+#[test]
+fn ll_log_full() {
+  // Linking a node between two others needs two locks, which is more than
+  // the log allows. That is reported as an error, not as a present key.
+  let mut ll = RluList::with_config(RluConfig {
+    log_limit: Some(1),
+    ..Default::default()
+  });
+  assert_eq!(ll.insert(1), Ok(true));
+  assert_eq!(
+    ll.insert(0),
+    Err(TransactionError::WriteLock(WriteLockError::LogFull))
+  );
+  assert!(ll.contains(0).is_none());
+  assert_eq!(
+    ll.delete(1),
+    Err(TransactionError::WriteLock(WriteLockError::LogFull))
+  );
+  assert!(ll.contains(1).is_some());
+}
 
+fn ll_stress(mut ll: RluList<usize>) {
   {
     for i in 0..1000 {
-      assert!(ll.insert(i).is_some());
+      assert_eq!(ll.insert(i), Ok(true));
     }
+    ll.flush();
   }
 
   let reader = || {
@@ -81,9 +121,9 @@ fn ll_thread() {
       for _ in 0..1000 {
         let i = rng.gen_range(0, 499) * 2 + 1;
         if random() {
-          ll.insert(i);
+          ll.insert(i).unwrap();
         } else {
-          ll.delete(i);
+          ll.delete(i).unwrap();
         }
       }
     })
@@ -0,0 +1,71 @@
+#![cfg(feature = "observer")]
+
+use std::sync::{Arc, Mutex};
+
+use rlu::{Rlu, RluConfig, RluEvent, RluObserver, WriteLockError};
+
+#[derive(Default)]
+struct Recorder {
+  events: Mutex<Vec<RluEvent>>,
+}
+
+impl RluObserver for Recorder {
+  fn on_event(&self, event: RluEvent) {
+    self.events.lock().unwrap().push(event);
+  }
+}
+
+#[test]
+fn observer_events() {
+  let recorder = Arc::new(Recorder::default());
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::with_config(RluConfig {
+    observer: Some(recorder.clone()),
+    ..Default::default()
+  }));
+  let obj = rlu.alloc(0);
+  let mut thread0 = rlu.thread();
+  let mut thread1 = rlu.thread();
+
+  {
+    let mut lock0 = thread0.session();
+    *lock0.write_lock(obj).unwrap() += 1;
+
+    let mut lock1 = thread1.session();
+    assert!(lock1.write_lock(obj).is_err());
+    lock1.abort();
+  }
+
+  assert_eq!(
+    *recorder.events.lock().unwrap(),
+    vec![
+      RluEvent::SessionBegin {
+        thread_id: 0,
+        local_clock: 0,
+      },
+      RluEvent::WriteLock {
+        thread_id: 0,
+        result: Ok(()),
+      },
+      RluEvent::SessionBegin {
+        thread_id: 1,
+        local_clock: 0,
+      },
+      RluEvent::WriteLock {
+        thread_id: 1,
+        result: Err(WriteLockError::HeldBy { thread_id: 0 }),
+      },
+      RluEvent::SessionEnd {
+        thread_id: 1,
+        aborted: true,
+      },
+      RluEvent::SessionEnd {
+        thread_id: 0,
+        aborted: false,
+      },
+      RluEvent::Commit {
+        thread_id: 0,
+        write_clock: 1,
+      },
+    ]
+  );
+}
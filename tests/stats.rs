@@ -0,0 +1,49 @@
+#![cfg(feature = "stats")]
+
+use std::sync::Arc;
+
+use rlu::{Rlu, RluStats};
+
+#[test]
+fn stats_counters() {
+  let rlu: Arc<Rlu<u64>> = Arc::new(Rlu::new());
+  let obj = rlu.alloc(0);
+  let mut thread0 = rlu.thread();
+  let mut thread1 = rlu.thread();
+
+  {
+    let mut lock0 = thread0.session();
+    *lock0.write_lock(obj).unwrap() += 1;
+
+    let mut lock1 = thread1.session();
+    assert!(lock1.write_lock(obj).is_err());
+    lock1.abort();
+  }
+
+  let garbage = rlu.alloc(0);
+  thread1.free(garbage);
+  thread1.wait_for_readers();
+
+  assert_eq!(
+    thread0.stats(),
+    RluStats {
+      sessions: 1,
+      commits: 1,
+      log_entries: 1,
+      ..Default::default()
+    }
+  );
+
+  assert_eq!(
+    rlu.stats(),
+    RluStats {
+      sessions: 2,
+      commits: 1,
+      aborts: 1,
+      write_lock_conflicts: 1,
+      log_entries: 1,
+      frees: 1,
+      ..Default::default()
+    }
+  );
+}